@@ -14,6 +14,13 @@ fn main() {
         Ok::<_, HttpError>(_res)
     });
 
+    app.on_panic(|panic, req| {
+        eprintln!("{} {} panicked: {}", req.method, req.uri, panic);
+        hyper::Response::builder()
+            .status(hyper::StatusCode::INTERNAL_SERVER_ERROR)
+            .body("Something went wrong".into())
+    });
+
     let app = app.build();
     let addr = ([127, 0, 0, 1], 3000).into();
     let server =
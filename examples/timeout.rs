@@ -2,15 +2,13 @@
 
 extern crate futures_await as futures;
 extern crate hyper;
-extern crate tokio_timer;
 extern crate web;
 
-use futures::future::{Either, Future};
+use futures::future::Future;
 use futures::prelude::{async, await};
 use futures::sync::oneshot;
-use hyper::{Body, StatusCode};
+use hyper::StatusCode;
 use std::time::Duration;
-use tokio_timer::sleep;
 use web::*;
 
 type Next = web::Next<()>;
@@ -29,18 +27,11 @@ fn main() {
     let mut app = App::new();
 
     // add a 1000ms timeout middleware
-    app.add(|req, res, ctx, next: Next| {
-        let sleep = sleep(Duration::from_millis(1000));
-        sleep.select2(next(req, res, ctx)).then(|res| match res {
-            Ok(Either::A(_)) => Response::new()
-                .status(StatusCode::REQUEST_TIMEOUT)
-                .body(Body::empty())
-                .into_http_response(),
-            Ok(Either::B((res, _))) => Ok(res),
-            Err(Either::A((err, _))) => panic!(err),
-            Err(Either::B((err, _))) => Err(err),
-        })
-    });
+    app.add(
+        timeout(Duration::from_millis(1000))
+            .status(StatusCode::REQUEST_TIMEOUT)
+            .on_timeout(|method, uri| eprintln!("{} {} timed out", method, uri)),
+    );
     app.add(handler);
 
     let app = app.build();
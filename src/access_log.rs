@@ -0,0 +1,618 @@
+use std::fmt::Write as _;
+use std::io::Write;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+
+use crate::{
+    HttpResponse, Middleware, Next, OriginalUri, RemoteAddr, Request, RequestId, Response,
+    ResponseFuture,
+};
+use futures::{Async, Future, Poll, Stream};
+use hyper::body::Payload;
+use hyper::header::{HeaderMap, HeaderName, CONTENT_LENGTH, REFERER, USER_AGENT};
+use hyper::{Body, Chunk, Method, StatusCode, Uri, Version};
+
+/// The format of the access log lines.
+#[derive(Debug, Clone)]
+pub enum LogFormat {
+    /// The Apache Common Log Format, `%h %l %u %t "%r" %>s %b`.
+    Common,
+    /// The Apache Combined Log Format, the common one followed by the referer and user agent.
+    Combined,
+    /// One JSON object per line, including the `RequestId` if one has been assigned.
+    Json,
+    /// An Apache style format string, supporting `%h`, `%l`, `%u`, `%t`, `%r`, `%m`, `%U`,
+    /// `%q`, `%H`, `%s`, `%>s`, `%b`, `%B`, `%D`, `%T`, `%{Header}i`, `%{Header}o` and `%%`.
+    Custom(String),
+}
+
+const COMMON: &str = "%h %l %u %t \"%r\" %>s %b";
+const COMBINED: &str = "%h %l %u %t \"%r\" %>s %b \"%{Referer}i\" \"%{User-Agent}i\"";
+
+#[derive(Clone)]
+pub struct AccessLog {
+    logger: Arc<Logger>,
+}
+
+#[derive(Clone)]
+struct Logger {
+    format: Option<Vec<Token>>,
+    request_headers: Vec<HeaderName>,
+    response_headers: Vec<HeaderName>,
+    sink: Sink,
+}
+
+#[derive(Clone)]
+enum Sink {
+    Log,
+    Writer(Arc<Mutex<Box<dyn Write + Send>>>),
+}
+
+/// Logs every request in the Apache Combined Log Format through the `log` facade, after the
+/// response has been sent.
+pub fn access_log() -> AccessLog {
+    AccessLog {
+        logger: Arc::new(Logger::new(LogFormat::Combined, Sink::Log)),
+    }
+}
+
+impl AccessLog {
+    /// Panics if a custom format string contains an unknown directive.
+    pub fn format(mut self, format: LogFormat) -> Self {
+        let sink = self.logger.sink.clone();
+        self.logger = Arc::new(Logger::new(format, sink));
+        self
+    }
+
+    /// Writes the log lines to `writer` instead of the `log` facade.
+    pub fn writer<W: Write + Send + 'static>(mut self, writer: W) -> Self {
+        Arc::make_mut(&mut self.logger).sink = Sink::Writer(Arc::new(Mutex::new(Box::new(writer))));
+        self
+    }
+}
+
+impl<S> Middleware<S> for AccessLog
+where
+    S: 'static,
+{
+    fn handle(&self, req: Request, res: Response, state: S, next: Next<S>) -> ResponseFuture {
+        let start = Instant::now();
+        let mut entry = Entry {
+            time: SystemTime::now(),
+            remote_addr: req.extensions().get::<RemoteAddr>().map(|addr| addr.0),
+            method: req.method().clone(),
+            uri: req
+                .extensions()
+                .get::<OriginalUri>()
+                .map(|uri| uri.0.clone())
+                .unwrap_or_else(|| req.uri().clone()),
+            version: req.version(),
+            request_id: req.extensions().get::<RequestId>().cloned(),
+            request_headers: select(req.headers(), &self.logger.request_headers),
+            status: StatusCode::OK,
+            response_headers: HeaderMap::new(),
+            size: None,
+            latency: Duration::default(),
+        };
+
+        let logger = self.logger.clone();
+        Box::new(next(req, res, state).then(move |result| {
+            entry.latency = start.elapsed();
+            let res = match result {
+                Ok(res) => res,
+                Err(err) => {
+                    entry.status = err.status();
+                    logger.log(&entry);
+                    return Err(err);
+                }
+            };
+
+            entry.status = res.status();
+            entry.response_headers = select(res.headers(), &logger.response_headers);
+            let (parts, body) = res.into_parts();
+            let size = body.content_length().or_else(|| {
+                parts
+                    .headers
+                    .get(CONTENT_LENGTH)
+                    .and_then(|v| v.to_str().ok())
+                    .and_then(|v| v.parse().ok())
+            });
+            match size {
+                Some(size) => {
+                    entry.size = Some(size);
+                    logger.log(&entry);
+                    Ok(HttpResponse::from_parts(parts, body))
+                }
+                None => {
+                    // log once the body has been sent to know its size
+                    let body = Counted {
+                        body,
+                        size: 0,
+                        entry: Some(entry),
+                        logger,
+                    };
+                    Ok(HttpResponse::from_parts(parts, Body::wrap_stream(body)))
+                }
+            }
+        }))
+    }
+}
+
+struct Entry {
+    time: SystemTime,
+    remote_addr: Option<SocketAddr>,
+    method: Method,
+    uri: Uri,
+    version: Version,
+    request_id: Option<RequestId>,
+    request_headers: HeaderMap,
+    status: StatusCode,
+    response_headers: HeaderMap,
+    size: Option<u64>,
+    latency: Duration,
+}
+
+/// Counts the bytes of a streamed body and logs the request once it ends or is dropped.
+struct Counted {
+    body: Body,
+    size: u64,
+    entry: Option<Entry>,
+    logger: Arc<Logger>,
+}
+
+impl Counted {
+    fn finish(&mut self) {
+        if let Some(mut entry) = self.entry.take() {
+            entry.size = Some(self.size);
+            self.logger.log(&entry);
+        }
+    }
+}
+
+impl Stream for Counted {
+    type Item = Chunk;
+    type Error = hyper::Error;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        let chunk = match self.body.poll() {
+            Ok(Async::Ready(chunk)) => chunk,
+            Ok(Async::NotReady) => return Ok(Async::NotReady),
+            Err(err) => {
+                self.finish();
+                return Err(err);
+            }
+        };
+        match chunk {
+            Some(ref chunk) => self.size += chunk.len() as u64,
+            None => self.finish(),
+        }
+        Ok(Async::Ready(chunk))
+    }
+}
+
+impl Drop for Counted {
+    fn drop(&mut self) {
+        self.finish();
+    }
+}
+
+fn select(headers: &HeaderMap, names: &[HeaderName]) -> HeaderMap {
+    let mut selected = HeaderMap::new();
+    for name in names {
+        if let Some(value) = headers.get(name) {
+            selected.insert(name.clone(), value.clone());
+        }
+    }
+    selected
+}
+
+#[derive(Debug, Clone)]
+enum Token {
+    Literal(String),
+    RemoteHost,
+    Dash,
+    Time,
+    RequestLine,
+    Method,
+    Path,
+    Query,
+    Protocol,
+    Status,
+    Size,
+    SizeOrZero,
+    Micros,
+    Secs,
+    RequestHeader(HeaderName),
+    ResponseHeader(HeaderName),
+}
+
+impl Logger {
+    fn new(format: LogFormat, sink: Sink) -> Self {
+        let format = match format {
+            LogFormat::Common => Some(parse(COMMON)),
+            LogFormat::Combined => Some(parse(COMBINED)),
+            LogFormat::Json => None,
+            LogFormat::Custom(format) => Some(parse(&format)),
+        };
+        let mut request_headers = Vec::new();
+        let mut response_headers = Vec::new();
+        match format {
+            Some(ref tokens) => {
+                for token in tokens {
+                    match *token {
+                        Token::RequestHeader(ref name) => request_headers.push(name.clone()),
+                        Token::ResponseHeader(ref name) => response_headers.push(name.clone()),
+                        _ => {}
+                    }
+                }
+            }
+            None => request_headers.extend_from_slice(&[REFERER, USER_AGENT]),
+        }
+
+        Logger {
+            format,
+            request_headers,
+            response_headers,
+            sink,
+        }
+    }
+
+    fn log(&self, entry: &Entry) {
+        let line = match self.format {
+            Some(ref tokens) => format_line(tokens, entry),
+            None => format_json(entry),
+        };
+        match self.sink {
+            Sink::Log => log::info!("{}", line),
+            Sink::Writer(ref writer) => {
+                let mut writer = writer.lock().unwrap();
+                if let Err(err) = writeln!(writer, "{}", line) {
+                    eprintln!("Error writing access log: {}", err);
+                }
+            }
+        }
+    }
+}
+
+fn parse(format: &str) -> Vec<Token> {
+    let mut tokens = Vec::new();
+    let mut literal = String::new();
+    let mut chars = format.chars();
+    while let Some(c) = chars.next() {
+        if c != '%' {
+            literal.push(c);
+            continue;
+        }
+
+        let mut directive = chars.next();
+        if directive == Some('>') {
+            directive = chars.next();
+        }
+        let token = match directive {
+            Some('%') => {
+                literal.push('%');
+                continue;
+            }
+            Some('h') => Token::RemoteHost,
+            Some('l') | Some('u') => Token::Dash,
+            Some('t') => Token::Time,
+            Some('r') => Token::RequestLine,
+            Some('m') => Token::Method,
+            Some('U') => Token::Path,
+            Some('q') => Token::Query,
+            Some('H') => Token::Protocol,
+            Some('s') => Token::Status,
+            Some('b') => Token::Size,
+            Some('B') => Token::SizeOrZero,
+            Some('D') => Token::Micros,
+            Some('T') => Token::Secs,
+            Some('{') => {
+                let name = chars.by_ref().take_while(|c| *c != '}').collect::<String>();
+                let name = HeaderName::from_bytes(name.to_lowercase().as_bytes())
+                    .unwrap_or_else(|_| panic!("invalid header name in log format: {}", name));
+                match chars.next() {
+                    Some('i') => Token::RequestHeader(name),
+                    Some('o') => Token::ResponseHeader(name),
+                    other => panic!("unsupported log format directive: %{{..}}{:?}", other),
+                }
+            }
+            other => panic!("unsupported log format directive: %{:?}", other),
+        };
+        if !literal.is_empty() {
+            tokens.push(Token::Literal(std::mem::take(&mut literal)));
+        }
+        tokens.push(token);
+    }
+    if !literal.is_empty() {
+        tokens.push(Token::Literal(literal));
+    }
+    tokens
+}
+
+fn format_line(tokens: &[Token], entry: &Entry) -> String {
+    let mut line = String::new();
+    for token in tokens {
+        let _ = match *token {
+            Token::Literal(ref s) => write!(line, "{}", s),
+            Token::RemoteHost => match entry.remote_addr {
+                Some(addr) => write!(line, "{}", addr.ip()),
+                None => write!(line, "-"),
+            },
+            Token::Dash => write!(line, "-"),
+            Token::Time => {
+                let (year, month, day, hour, min, sec) = civil(entry.time);
+                write!(
+                    line,
+                    "[{:02}/{}/{}:{:02}:{:02}:{:02} +0000]",
+                    day,
+                    MONTHS[month as usize - 1],
+                    year,
+                    hour,
+                    min,
+                    sec
+                )
+            }
+            Token::RequestLine => write!(
+                line,
+                "{} {} {:?}",
+                entry.method,
+                path_and_query(&entry.uri),
+                entry.version
+            ),
+            Token::Method => write!(line, "{}", entry.method),
+            Token::Path => write!(line, "{}", entry.uri.path()),
+            Token::Query => match entry.uri.query() {
+                Some(query) => write!(line, "?{}", query),
+                None => Ok(()),
+            },
+            Token::Protocol => write!(line, "{:?}", entry.version),
+            Token::Status => write!(line, "{}", entry.status.as_u16()),
+            Token::Size => match entry.size {
+                Some(size) if size > 0 => write!(line, "{}", size),
+                _ => write!(line, "-"),
+            },
+            Token::SizeOrZero => write!(line, "{}", entry.size.unwrap_or(0)),
+            Token::Micros => write!(line, "{}", entry.latency.as_micros()),
+            Token::Secs => write!(line, "{}", entry.latency.as_secs()),
+            Token::RequestHeader(ref name) => header(&mut line, &entry.request_headers, name),
+            Token::ResponseHeader(ref name) => header(&mut line, &entry.response_headers, name),
+        };
+    }
+    line
+}
+
+fn header(line: &mut String, headers: &HeaderMap, name: &HeaderName) -> std::fmt::Result {
+    match headers.get(name).and_then(|v| v.to_str().ok()) {
+        Some(value) => write!(line, "{}", value.replace('"', "\\\"")),
+        None => write!(line, "-"),
+    }
+}
+
+fn format_json(entry: &Entry) -> String {
+    let (year, month, day, hour, min, sec) = civil(entry.time);
+    let mut line = format!(
+        "{{\"time\":\"{}-{:02}-{:02}T{:02}:{:02}:{:02}Z\",\"remote_addr\":",
+        year, month, day, hour, min, sec
+    );
+    match entry.remote_addr {
+        Some(addr) => json_string(&mut line, &addr.ip().to_string()),
+        None => line.push_str("null"),
+    }
+    line.push_str(",\"method\":");
+    json_string(&mut line, entry.method.as_str());
+    line.push_str(",\"uri\":");
+    json_string(&mut line, &path_and_query(&entry.uri));
+    let _ = write!(
+        line,
+        ",\"protocol\":\"{:?}\",\"status\":{},\"size\":",
+        entry.version,
+        entry.status.as_u16()
+    );
+    match entry.size {
+        Some(size) => {
+            let _ = write!(line, "{}", size);
+        }
+        None => line.push_str("null"),
+    }
+    let _ = write!(
+        line,
+        ",\"duration_ms\":{:.3}",
+        entry.latency.as_secs_f64() * 1000.0
+    );
+    line.push_str(",\"request_id\":");
+    match entry.request_id {
+        Some(ref id) => json_string(&mut line, &id.0),
+        None => line.push_str("null"),
+    }
+    for (key, name) in &[("referer", REFERER), ("user_agent", USER_AGENT)] {
+        let _ = write!(line, ",\"{}\":", key);
+        match entry
+            .request_headers
+            .get(name)
+            .and_then(|v| v.to_str().ok())
+        {
+            Some(value) => json_string(&mut line, value),
+            None => line.push_str("null"),
+        }
+    }
+    line.push('}');
+    line
+}
+
+fn json_string(out: &mut String, s: &str) {
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => {
+                let _ = write!(out, "\\u{:04x}", c as u32);
+            }
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+}
+
+fn path_and_query(uri: &Uri) -> String {
+    uri.path_and_query()
+        .map(|pq| pq.as_str().to_owned())
+        .unwrap_or_else(|| "/".to_owned())
+}
+
+const MONTHS: [&str; 12] = [
+    "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+];
+
+/// Splits `time` into year, month, day, hour, minute and second in UTC.
+fn civil(time: SystemTime) -> (i64, u32, u32, u32, u32, u32) {
+    let secs = time
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    let days = (secs / 86_400) as i64;
+    let rem = secs % 86_400;
+
+    // days to civil date, see http://howardhinnant.github.io/date_algorithms.html
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    (
+        year,
+        month,
+        day,
+        (rem / 3600) as u32,
+        (rem % 3600 / 60) as u32,
+        (rem % 60) as u32,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{civil, LogFormat};
+    use crate::{access_log, default_fallback, mount, App, HttpError, RemoteAddr, Response};
+    use futures::{Future, Stream};
+    use hyper::header::{REFERER, USER_AGENT};
+    use hyper::{Body, StatusCode};
+    use std::io::{self, Write};
+    use std::sync::{Arc, Mutex};
+    use std::time::{Duration, SystemTime};
+
+    #[derive(Clone, Default)]
+    struct Buffer(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Buffer {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn execute(format: LogFormat, streamed: bool) -> String {
+        let buffer = Buffer::default();
+        let mut app = App::new();
+        app.add(access_log().format(format).writer(buffer.clone()));
+        app.add(mount("/api", move |_, mut res: Response, _: (), _| {
+            let body = if streamed {
+                Body::wrap_stream(futures::stream::iter_ok::<_, io::Error>(vec!["ab", "cd"]))
+            } else {
+                Body::from("abcd")
+            };
+            res.status(StatusCode::CREATED)
+                .body(body)
+                .map_err(HttpError::Http)
+        }));
+
+        let mut req = hyper::Request::post("/api/users?page=2")
+            .header(USER_AGENT, "curl/7.64.1")
+            .header(REFERER, "http://example.com/\"quoted\"")
+            .body(Body::empty())
+            .unwrap();
+        req.extensions_mut()
+            .insert(RemoteAddr(([127, 0, 0, 1], 52_000).into()));
+        let res = app
+            .build()
+            .execute(req, Response::new(), (), default_fallback)
+            .wait()
+            .unwrap();
+        res.into_body().concat2().wait().unwrap();
+
+        let log = buffer.0.lock().unwrap();
+        String::from_utf8(log.clone()).unwrap()
+    }
+
+    #[test]
+    fn combined_format() {
+        let line = execute(LogFormat::Combined, false);
+        assert!(line.starts_with("127.0.0.1 - - ["));
+        assert!(line.ends_with(
+            "] \"POST /api/users?page=2 HTTP/1.1\" 201 4 \
+             \"http://example.com/\\\"quoted\\\"\" \"curl/7.64.1\"\n"
+        ));
+    }
+
+    #[test]
+    fn counts_streamed_bodies() {
+        let line = execute(LogFormat::Custom("%m %U%q %>s %b %B %%".to_owned()), true);
+        assert_eq!(line, "POST /api/users?page=2 201 4 4 %\n");
+    }
+
+    #[test]
+    fn json_format() {
+        let line = execute(LogFormat::Json, false);
+        assert!(line.starts_with("{\"time\":\""));
+        assert!(line.contains(
+            "\"remote_addr\":\"127.0.0.1\",\"method\":\"POST\",\"uri\":\"/api/users?page=2\",\
+             \"protocol\":\"HTTP/1.1\",\"status\":201,\"size\":4,\"duration_ms\":"
+        ));
+        assert!(line.ends_with(
+            "\"request_id\":null,\"referer\":\"http://example.com/\\\"quoted\\\"\",\"user_agent\":\"curl/7.64.1\"}\n"
+        ));
+    }
+
+    #[test]
+    fn configures_cloned_builders() {
+        let common = Buffer::default();
+        let custom = Buffer::default();
+        let log = access_log().format(LogFormat::Common);
+        let mut app = App::new();
+        app.add(log.clone().writer(common.clone()));
+        app.add(
+            log.format(LogFormat::Custom("%m %U".to_owned()))
+                .writer(custom.clone()),
+        );
+        app.add(|_, res: Response, _: (), _| Ok::<_, HttpError>(res));
+
+        let req = hyper::Request::get("/users").body(Body::empty()).unwrap();
+        app.build()
+            .execute(req, Response::new(), (), default_fallback)
+            .wait()
+            .unwrap();
+
+        let common = String::from_utf8(common.0.lock().unwrap().clone()).unwrap();
+        assert!(common.ends_with("] \"GET /users HTTP/1.1\" 200 -\n"));
+        assert_eq!(*custom.0.lock().unwrap(), b"GET /users\n");
+    }
+
+    #[test]
+    fn civil_dates() {
+        let time = SystemTime::UNIX_EPOCH + Duration::from_secs(784_111_777);
+        assert_eq!(civil(time), (1994, 11, 6, 8, 49, 37));
+        let time = SystemTime::UNIX_EPOCH + Duration::from_secs(951_782_400);
+        assert_eq!(civil(time), (2000, 2, 29, 0, 0, 0));
+    }
+}
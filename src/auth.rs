@@ -0,0 +1,290 @@
+use std::fmt;
+use std::sync::Arc;
+
+use crate::{HttpError, HttpResponse, Middleware, Next, Request, Response, ResponseFuture};
+use futures::future::{self, Either, Future, IntoFuture};
+use hyper::header::{HeaderValue, AUTHORIZATION, WWW_AUTHENTICATE};
+use hyper::{Body, StatusCode};
+
+pub type VerifyFuture<P> = Box<dyn Future<Item = Option<P>, Error = HttpError> + Send>;
+
+type Verify<C, P> = dyn Fn(C) -> VerifyFuture<P> + Send + Sync;
+
+/// The username and password sent with HTTP Basic authentication.
+#[derive(Clone, PartialEq, Eq)]
+pub struct Credentials {
+    pub username: String,
+    pub password: String,
+}
+
+impl fmt::Debug for Credentials {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Credentials")
+            .field("username", &self.username)
+            .field("password", &"***")
+            .finish()
+    }
+}
+
+pub struct BasicAuth<P> {
+    realm: String,
+    verify: Arc<Verify<Credentials, P>>,
+}
+
+pub struct BearerAuth<P> {
+    realm: String,
+    verify: Arc<Verify<String, P>>,
+}
+
+/// Requires HTTP Basic authentication. `verify` is called with the credentials of each request and
+/// returns the authenticated principal, e.g. a user, or `None` if the credentials are invalid. It
+/// can either return a `Result` or, to verify asynchronously, a future.
+///
+/// The principal is put into the request extensions. Requests without valid credentials are
+/// answered with `401 Unauthorized` and a `WWW-Authenticate` challenge for `realm`.
+pub fn basic_auth<P, F, R>(realm: &str, verify: F) -> BasicAuth<P>
+where
+    F: Fn(Credentials) -> R + Send + Sync + 'static,
+    R: IntoFuture<Item = Option<P>, Error = HttpError>,
+    R::Future: Send + 'static,
+{
+    BasicAuth {
+        realm: realm.to_owned(),
+        verify: Arc::new(move |credentials| Box::new(verify(credentials).into_future())),
+    }
+}
+
+/// Requires a Bearer token (RFC 6750). `verify` is called with the token of each request, see
+/// `basic_auth`.
+pub fn bearer_auth<P, F, R>(realm: &str, verify: F) -> BearerAuth<P>
+where
+    F: Fn(String) -> R + Send + Sync + 'static,
+    R: IntoFuture<Item = Option<P>, Error = HttpError>,
+    R::Future: Send + 'static,
+{
+    BearerAuth {
+        realm: realm.to_owned(),
+        verify: Arc::new(move |token| Box::new(verify(token).into_future())),
+    }
+}
+
+impl<P> Clone for BasicAuth<P> {
+    fn clone(&self) -> Self {
+        BasicAuth {
+            realm: self.realm.clone(),
+            verify: self.verify.clone(),
+        }
+    }
+}
+
+impl<P> Clone for BearerAuth<P> {
+    fn clone(&self) -> Self {
+        BearerAuth {
+            realm: self.realm.clone(),
+            verify: self.verify.clone(),
+        }
+    }
+}
+
+impl<S, P> Middleware<S> for BasicAuth<P>
+where
+    S: Send + 'static,
+    P: Send + Sync + 'static,
+{
+    fn handle(&self, req: Request, res: Response, state: S, next: Next<S>) -> ResponseFuture {
+        let challenge = format!("Basic realm={}, charset=\"UTF-8\"", quote(&self.realm));
+        match credentials(&req, "Basic").and_then(parse_basic) {
+            Some(credentials) => {
+                authenticate((self.verify)(credentials), challenge, req, res, state, next)
+            }
+            None => Box::new(future::err(unauthorized(challenge))),
+        }
+    }
+}
+
+impl<S, P> Middleware<S> for BearerAuth<P>
+where
+    S: Send + 'static,
+    P: Send + Sync + 'static,
+{
+    fn handle(&self, req: Request, res: Response, state: S, next: Next<S>) -> ResponseFuture {
+        let realm = quote(&self.realm);
+        match credentials(&req, "Bearer") {
+            Some(token) => {
+                let challenge = format!("Bearer realm={}, error=\"invalid_token\"", realm);
+                authenticate((self.verify)(token), challenge, req, res, state, next)
+            }
+            None => Box::new(future::err(unauthorized(format!("Bearer realm={}", realm)))),
+        }
+    }
+}
+
+fn authenticate<S, P>(
+    verify: VerifyFuture<P>,
+    challenge: String,
+    mut req: Request,
+    res: Response,
+    state: S,
+    next: Next<S>,
+) -> ResponseFuture
+where
+    S: Send + 'static,
+    P: Send + Sync + 'static,
+{
+    Box::new(verify.and_then(move |principal| match principal {
+        Some(principal) => {
+            req.extensions_mut().insert(principal);
+            Either::A(next(req, res, state))
+        }
+        None => Either::B(future::err(unauthorized(challenge))),
+    }))
+}
+
+/// The credentials of the `Authorization` header if it uses `scheme`.
+pub(crate) fn credentials(req: &Request, scheme: &str) -> Option<String> {
+    let value = req.headers().get(AUTHORIZATION)?.to_str().ok()?.trim();
+    let mut parts = value.splitn(2, ' ');
+    if !parts.next()?.eq_ignore_ascii_case(scheme) {
+        return None;
+    }
+    let credentials = parts.next()?.trim();
+    if credentials.is_empty() {
+        None
+    } else {
+        Some(credentials.to_owned())
+    }
+}
+
+fn parse_basic(encoded: String) -> Option<Credentials> {
+    let decoded = String::from_utf8(base64::decode(&encoded).ok()?).ok()?;
+    let mut parts = decoded.splitn(2, ':');
+    Some(Credentials {
+        username: parts.next()?.to_owned(),
+        password: parts.next()?.to_owned(),
+    })
+}
+
+pub(crate) fn quote(s: &str) -> String {
+    format!("\"{}\"", s.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+pub(crate) fn unauthorized(challenge: String) -> HttpError {
+    let status = StatusCode::UNAUTHORIZED;
+    let mut res = HttpResponse::new(Body::from(status.canonical_reason().unwrap_or_default()));
+    *res.status_mut() = status;
+    match HeaderValue::from_str(&challenge) {
+        Ok(value) => {
+            res.headers_mut().insert(WWW_AUTHENTICATE, value);
+            HttpError::Response(res)
+        }
+        Err(err) => HttpError::internal(err),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Credentials;
+    use crate::{
+        basic_auth, bearer_auth, default_fallback, App, HttpError, HttpResponse, Next, Request,
+        Response,
+    };
+    use futures::future::{self, Future};
+    use hyper::header::{AUTHORIZATION, WWW_AUTHENTICATE};
+    use hyper::{Body, StatusCode};
+
+    #[derive(Debug, PartialEq)]
+    struct User(String);
+
+    fn app<M: crate::Middleware<()> + 'static>(auth: M) -> App<()> {
+        let mut app = App::new();
+        app.add(auth);
+        app.add(|req: Request, mut res: Response, _state, _next: Next<()>| {
+            let user = req.extensions().get::<User>().unwrap();
+            res.body(Body::from(user.0.clone()))
+        });
+        app.build()
+    }
+
+    fn call(app: &App<()>, authorization: Option<&str>) -> Result<HttpResponse, HttpError> {
+        let mut req = hyper::Request::get("/");
+        if let Some(authorization) = authorization {
+            req.header(AUTHORIZATION, authorization);
+        }
+        app.execute(
+            req.body(Body::empty()).unwrap(),
+            Response::new(),
+            (),
+            default_fallback,
+        )
+        .wait()
+    }
+
+    fn challenge(result: Result<HttpResponse, HttpError>) -> String {
+        match result {
+            Err(HttpError::Response(res)) => {
+                assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+                res.headers()[WWW_AUTHENTICATE].to_str().unwrap().to_owned()
+            }
+            _ => panic!("expected unauthorized"),
+        }
+    }
+
+    #[test]
+    fn basic() {
+        let app = app(basic_auth("admin \"area\"", |credentials: Credentials| {
+            if credentials.username == "aladdin" && credentials.password == "open:sesame" {
+                Ok(Some(User(credentials.username)))
+            } else {
+                Ok(None)
+            }
+        }));
+
+        // aladdin:open:sesame
+        let res = call(&app, Some("basic YWxhZGRpbjpvcGVuOnNlc2FtZQ==")).unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+
+        let expected = "Basic realm=\"admin \\\"area\\\"\", charset=\"UTF-8\"";
+        assert_eq!(challenge(call(&app, None)), expected);
+        assert_eq!(challenge(call(&app, Some("Basic !!!"))), expected);
+        // aladdin:wrong
+        assert_eq!(
+            challenge(call(&app, Some("Basic YWxhZGRpbjp3cm9uZw=="))),
+            expected
+        );
+    }
+
+    #[test]
+    fn bearer() {
+        let app = app(bearer_auth("api", |token: String| {
+            future::ok(if token == "secret" {
+                Some(User("service".to_owned()))
+            } else {
+                None
+            })
+        }));
+
+        let res = call(&app, Some("Bearer secret")).unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+
+        assert_eq!(challenge(call(&app, None)), "Bearer realm=\"api\"");
+        assert_eq!(
+            challenge(call(&app, Some("Bearer wrong"))),
+            "Bearer realm=\"api\", error=\"invalid_token\""
+        );
+        assert_eq!(
+            challenge(call(&app, Some("Basic c2VjcmV0"))),
+            "Bearer realm=\"api\""
+        );
+    }
+
+    #[test]
+    fn verifier_errors() {
+        let app = app(bearer_auth("api", |_token: String| {
+            Err::<Option<User>, _>(HttpError::Status(StatusCode::SERVICE_UNAVAILABLE))
+        }));
+        match call(&app, Some("Bearer secret")) {
+            Err(HttpError::Status(status)) => assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE),
+            _ => panic!("expected service unavailable"),
+        }
+    }
+}
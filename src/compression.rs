@@ -0,0 +1,360 @@
+use std::error::Error as StdError;
+use std::io::{self, Write};
+use std::mem;
+
+use crate::encoding::{self, Encoding};
+use crate::{HttpResponse, Middleware, Next, Request, Response, ResponseFuture};
+use brotli::CompressorWriter;
+use flate2::write::{GzEncoder, ZlibEncoder};
+use futures::{Async, Future, Poll, Stream};
+use hyper::body::Payload;
+use hyper::header::{
+    HeaderValue, ACCEPT_RANGES, CONTENT_ENCODING, CONTENT_LENGTH, CONTENT_TYPE, ETAG, VARY,
+};
+use hyper::{Body, Chunk, Method, StatusCode};
+
+#[derive(Clone)]
+pub struct Compression {
+    encodings: Vec<Encoding>,
+    min_size: u64,
+    content_types: Vec<String>,
+}
+
+/// Compresses response bodies with the encoding the client prefers according to its
+/// `Accept-Encoding` header.
+pub fn compression() -> Compression {
+    Compression {
+        encodings: vec![Encoding::Brotli, Encoding::Gzip, Encoding::Deflate],
+        min_size: 1024,
+        content_types: [
+            "text/",
+            "application/javascript",
+            "application/json",
+            "application/problem+json",
+            "application/xml",
+            "image/svg+xml",
+        ]
+        .iter()
+        .map(|s| (*s).to_owned())
+        .collect(),
+    }
+}
+
+impl Compression {
+    /// The encodings that may be used, in the order they are preferred if the client accepts
+    /// several of them equally (defaults to brotli, gzip and deflate).
+    pub fn encodings(mut self, encodings: &[Encoding]) -> Self {
+        self.encodings = encodings
+            .iter()
+            .cloned()
+            .filter(|enc| *enc != Encoding::Identity)
+            .collect();
+        self
+    }
+
+    /// Responses with a known length below `size` bytes are sent uncompressed (defaults to 1024).
+    pub fn min_size(mut self, size: u64) -> Self {
+        self.min_size = size;
+        self
+    }
+
+    /// Only compress responses whose `Content-Type` starts with one of `prefixes`, e.g.
+    /// `"text/"` or `"application/json"`.
+    pub fn content_types(mut self, prefixes: &[&str]) -> Self {
+        self.content_types = prefixes.iter().map(|s| (*s).to_owned()).collect();
+        self
+    }
+
+    fn compressible(&self, res: &HttpResponse) -> bool {
+        if res.status() == StatusCode::NO_CONTENT
+            || res.status() == StatusCode::NOT_MODIFIED
+            || res.status() == StatusCode::PARTIAL_CONTENT
+            || res.headers().contains_key(CONTENT_ENCODING)
+        {
+            return false;
+        }
+
+        let content_type = match res
+            .headers()
+            .get(CONTENT_TYPE)
+            .and_then(|v| v.to_str().ok())
+        {
+            Some(content_type) => content_type,
+            None => return false,
+        };
+        if !self
+            .content_types
+            .iter()
+            .any(|prefix| content_type.starts_with(prefix.as_str()))
+        {
+            return false;
+        }
+
+        content_length(res)
+            .or_else(|| res.body().content_length())
+            .map(|len| len >= self.min_size)
+            .unwrap_or(true)
+    }
+}
+
+impl<S> Middleware<S> for Compression
+where
+    S: 'static,
+{
+    fn handle(&self, req: Request, res: Response, state: S, next: Next<S>) -> ResponseFuture {
+        if req.method() == Method::HEAD {
+            return next(req, res, state);
+        }
+
+        let mut available = self.encodings.clone();
+        available.push(Encoding::Identity);
+        let encoding = encoding::negotiate(req.headers(), &available).unwrap_or(Encoding::Identity);
+
+        let config = self.clone();
+        Box::new(next(req, res, state).and_then(move |res| {
+            if !config.compressible(&res) {
+                return Box::new(futures::future::ok(res)) as ResponseFuture;
+            }
+
+            let (mut parts, body) = res.into_parts();
+            parts
+                .headers
+                .append(VARY, HeaderValue::from_static("Accept-Encoding"));
+            if encoding == Encoding::Identity {
+                return Box::new(futures::future::ok(HttpResponse::from_parts(parts, body)));
+            }
+
+            parts.headers.insert(
+                CONTENT_ENCODING,
+                HeaderValue::from_static(encoding.as_str()),
+            );
+            parts.headers.remove(ACCEPT_RANGES);
+            // the compressed representation is not byte-for-byte identical anymore
+            if let Some(etag) = parts.headers.get(ETAG).cloned() {
+                if !etag.as_bytes().starts_with(b"W/") {
+                    let mut weak = b"W/".to_vec();
+                    weak.extend_from_slice(etag.as_bytes());
+                    if let Ok(weak) = HeaderValue::from_bytes(&weak) {
+                        parts.headers.insert(ETAG, weak);
+                    }
+                }
+            }
+
+            // the compressed length is only known once the whole body has been compressed
+            parts.headers.remove(CONTENT_LENGTH);
+            let stream = Compress {
+                body,
+                encoder: Some(Encoder::new(encoding)),
+            };
+            Box::new(futures::future::ok(HttpResponse::from_parts(
+                parts,
+                Body::wrap_stream(stream),
+            )))
+        }))
+    }
+}
+
+fn content_length(res: &HttpResponse) -> Option<u64> {
+    res.headers()
+        .get(CONTENT_LENGTH)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse().ok())
+}
+
+enum Encoder {
+    Brotli(Box<CompressorWriter<Vec<u8>>>),
+    Gzip(GzEncoder<Vec<u8>>),
+    Deflate(ZlibEncoder<Vec<u8>>),
+}
+
+impl Encoder {
+    fn new(encoding: Encoding) -> Self {
+        match encoding {
+            // favor speed over size, since responses are compressed on the fly
+            Encoding::Brotli => {
+                Encoder::Brotli(Box::new(CompressorWriter::new(Vec::new(), 4096, 5, 22)))
+            }
+            Encoding::Gzip => Encoder::Gzip(GzEncoder::new(Vec::new(), Default::default())),
+            Encoding::Deflate => Encoder::Deflate(ZlibEncoder::new(Vec::new(), Default::default())),
+            Encoding::Identity => unreachable!("identity does not need an encoder"),
+        }
+    }
+
+    fn write(&mut self, data: &[u8]) -> io::Result<()> {
+        match self {
+            Encoder::Brotli(w) => w.write_all(data),
+            Encoder::Gzip(w) => w.write_all(data),
+            Encoder::Deflate(w) => w.write_all(data),
+        }
+    }
+
+    /// Flushes and returns everything that has been compressed so far.
+    fn take(&mut self) -> io::Result<Vec<u8>> {
+        let buf = match self {
+            Encoder::Brotli(w) => {
+                w.flush()?;
+                w.get_mut()
+            }
+            Encoder::Gzip(w) => {
+                w.flush()?;
+                w.get_mut()
+            }
+            Encoder::Deflate(w) => {
+                w.flush()?;
+                w.get_mut()
+            }
+        };
+        Ok(mem::take(buf))
+    }
+
+    fn finish(self) -> io::Result<Vec<u8>> {
+        match self {
+            Encoder::Brotli(w) => Ok(w.into_inner()),
+            Encoder::Gzip(w) => w.finish(),
+            Encoder::Deflate(w) => w.finish(),
+        }
+    }
+}
+
+struct Compress {
+    body: Body,
+    encoder: Option<Encoder>,
+}
+
+impl Stream for Compress {
+    type Item = Chunk;
+    type Error = Box<dyn StdError + Send + Sync>;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        loop {
+            let chunk = match self.encoder {
+                Some(_) => futures::try_ready!(self.body.poll()),
+                None => return Ok(Async::Ready(None)),
+            };
+
+            let out = match chunk {
+                Some(chunk) => {
+                    let encoder = self.encoder.as_mut().unwrap();
+                    encoder.write(&chunk)?;
+                    encoder.take()?
+                }
+                None => self.encoder.take().unwrap().finish()?,
+            };
+            if !out.is_empty() {
+                return Ok(Async::Ready(Some(out.into())));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{compression, default_fallback, App, HttpError, HttpResponse, Request, Response};
+    use flate2::read::GzDecoder;
+    use futures::{Future, Stream};
+    use hyper::header::{ACCEPT_ENCODING, CONTENT_ENCODING, CONTENT_LENGTH, CONTENT_TYPE, VARY};
+    use hyper::Body;
+    use std::io::{self, Read};
+
+    fn execute(body: &'static str, content_type: &'static str, req: Request) -> HttpResponse {
+        let mut app = App::new();
+        app.add(compression().min_size(16));
+        app.add(move |_, mut res: Response, _: (), _| {
+            res.header(CONTENT_TYPE, content_type)
+                .header(CONTENT_LENGTH, body.len())
+                .body(Body::from(body))
+                .map_err(HttpError::Http)
+        });
+        app.build()
+            .execute(req, Response::new(), (), default_fallback)
+            .wait()
+            .unwrap()
+    }
+
+    fn collect(res: HttpResponse) -> hyper::Response<Vec<u8>> {
+        let (parts, body) = res.into_parts();
+        hyper::Response::from_parts(parts, body.concat2().wait().unwrap().to_vec())
+    }
+
+    const TEXT: &str = "Hello World! Hello World! Hello World! Hello World!";
+
+    #[test]
+    fn compresses_with_gzip() {
+        let req = hyper::Request::get("/")
+            .header(ACCEPT_ENCODING, "deflate;q=0.5, gzip")
+            .body(Body::empty())
+            .unwrap();
+        let res = collect(execute(TEXT, "text/plain", req));
+
+        assert_eq!(res.headers()[CONTENT_ENCODING], "gzip");
+        assert_eq!(res.headers()[VARY], "Accept-Encoding");
+        assert!(res.headers().get(CONTENT_LENGTH).is_none());
+        let mut decoded = String::new();
+        GzDecoder::new(&res.body()[..])
+            .read_to_string(&mut decoded)
+            .unwrap();
+        assert_eq!(decoded, TEXT);
+    }
+
+    #[test]
+    fn skips_small_and_unlisted_responses() {
+        let req = || {
+            hyper::Request::get("/")
+                .header(ACCEPT_ENCODING, "gzip")
+                .body(Body::empty())
+                .unwrap()
+        };
+
+        let res = execute("Hello", "text/plain", req());
+        assert!(res.headers().get(CONTENT_ENCODING).is_none());
+
+        let res = execute(TEXT, "image/png", req());
+        assert!(res.headers().get(CONTENT_ENCODING).is_none());
+        assert!(res.headers().get(VARY).is_none());
+
+        // the length of the body counts without a Content-Length header, too
+        let mut app = App::new();
+        app.add(compression().min_size(16));
+        app.add(|_, mut res: Response, _: (), _| {
+            res.header(CONTENT_TYPE, "text/plain")
+                .body(Body::from("Hello"))
+                .map_err(HttpError::Http)
+        });
+        let res = app
+            .build()
+            .execute(req(), Response::new(), (), default_fallback)
+            .wait()
+            .unwrap();
+        assert!(res.headers().get(CONTENT_ENCODING).is_none());
+    }
+
+    #[test]
+    fn streams_unknown_length() {
+        let mut app = App::new();
+        app.add(compression());
+        app.add(|_, mut res: Response, _: (), _| {
+            let chunks = futures::stream::iter_ok::<_, io::Error>(vec![TEXT, TEXT]);
+            res.header(CONTENT_TYPE, "text/plain")
+                .body(Body::wrap_stream(chunks))
+                .map_err(HttpError::Http)
+        });
+        let req = hyper::Request::get("/")
+            .header(ACCEPT_ENCODING, "br")
+            .body(Body::empty())
+            .unwrap();
+        let res = app
+            .build()
+            .execute(req, Response::new(), (), default_fallback)
+            .wait()
+            .unwrap();
+        let res = collect(res);
+
+        assert_eq!(res.headers()[CONTENT_ENCODING], "br");
+        assert!(res.headers().get(CONTENT_LENGTH).is_none());
+        let mut decoded = String::new();
+        brotli::Decompressor::new(&res.body()[..], 4096)
+            .read_to_string(&mut decoded)
+            .unwrap();
+        assert_eq!(decoded, TEXT.repeat(2));
+    }
+}
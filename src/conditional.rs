@@ -0,0 +1,396 @@
+use std::time::SystemTime;
+
+use crate::etag::etag;
+use crate::{HttpError, HttpResponse, Middleware, Next, Request, Response, ResponseFuture};
+use futures::{future, Future, Stream};
+use hyper::body::Payload;
+use hyper::header::{
+    HeaderMap, HeaderName, HeaderValue, CONTENT_LENGTH, CONTENT_RANGE, CONTENT_TYPE, ETAG,
+    IF_MATCH, IF_MODIFIED_SINCE, IF_NONE_MATCH, IF_UNMODIFIED_SINCE, LAST_MODIFIED,
+    TRANSFER_ENCODING,
+};
+use hyper::{Body, Method, StatusCode};
+
+pub struct Conditional;
+
+/// Evaluates the conditional request headers (`If-Match`, `If-None-Match`, `If-Modified-Since`
+/// and `If-Unmodified-Since`) against successful responses to `GET` and `HEAD` requests and
+/// replies with `304 Not Modified` or `412 Precondition Failed` accordingly. Responses without an
+/// `ETag` get a strong one computed from their body, if the body is already buffered and not only
+/// a part of the representation. Responses to `HEAD` requests only get one if the handler
+/// produced the same body as for `GET`.
+///
+/// Preconditions of state-changing requests have to be checked before the change is made, use
+/// [`check_preconditions`] from within the handler for those.
+pub fn conditional() -> Conditional {
+    Conditional
+}
+
+/// Evaluates the preconditions of `req` against the current `etag` and `last_modified` date of
+/// the targeted resource. If the request should not be performed, `res` is used to build an empty
+/// `304 Not Modified` (for `GET` and `HEAD`) or `412 Precondition Failed` response with the given
+/// validators, which is returned as an error. Headers already set on `res`, e.g. `Cache-Control`,
+/// are kept.
+pub fn check_preconditions(
+    req: &Request,
+    res: &mut Response,
+    etag: Option<&str>,
+    last_modified: Option<SystemTime>,
+) -> Result<(), HttpError> {
+    let status = match evaluate(req.method(), req.headers(), etag, last_modified) {
+        Outcome::Proceed => return Ok(()),
+        Outcome::NotModified => StatusCode::NOT_MODIFIED,
+        Outcome::PreconditionFailed => StatusCode::PRECONDITION_FAILED,
+    };
+    res.status(status);
+    if let Some(etag) = etag {
+        res.header(ETAG, etag);
+    }
+    if let Some(last_modified) = last_modified {
+        res.header(
+            LAST_MODIFIED,
+            httpdate::fmt_http_date(last_modified).as_str(),
+        );
+    }
+    match res.body(Body::empty()) {
+        Ok(res) => Err(HttpError::Response(res)),
+        Err(err) => Err(HttpError::Http(err)),
+    }
+}
+
+impl<S> Middleware<S> for Conditional
+where
+    S: 'static,
+{
+    fn handle(&self, req: Request, res: Response, state: S, next: Next<S>) -> ResponseFuture {
+        let method = req.method().clone();
+        if method != Method::GET && method != Method::HEAD {
+            return next(req, res, state);
+        }
+
+        let mut conditions = HeaderMap::new();
+        for name in &[
+            IF_MATCH,
+            IF_NONE_MATCH,
+            IF_MODIFIED_SINCE,
+            IF_UNMODIFIED_SINCE,
+        ] {
+            for value in req.headers().get_all(name) {
+                conditions.append(name, value.clone());
+            }
+        }
+
+        Box::new(next(req, res, state).and_then(move |res| {
+            if !res.status().is_success() {
+                return Box::new(future::ok(res)) as ResponseFuture;
+            }
+
+            let (mut parts, body) = res.into_parts();
+            let length = body.content_length();
+            if parts.headers.contains_key(ETAG)
+                || parts.status == StatusCode::PARTIAL_CONTENT
+                || parts.headers.contains_key(CONTENT_RANGE)
+                || length.is_none()
+                || (method == Method::HEAD && length == Some(0))
+            {
+                let res = HttpResponse::from_parts(parts, body);
+                return Box::new(future::ok(respond(&method, &conditions, res)));
+            }
+
+            Box::new(
+                body.concat2()
+                    .map_err(|_| HttpError::Status(StatusCode::INTERNAL_SERVER_ERROR))
+                    .map(move |body| {
+                        if let Ok(etag) = HeaderValue::from_str(&etag(&body)) {
+                            parts.headers.insert(ETAG, etag);
+                        }
+                        let res = HttpResponse::from_parts(parts, body.into());
+                        respond(&method, &conditions, res)
+                    }),
+            )
+        }))
+    }
+}
+
+fn respond(method: &Method, conditions: &HeaderMap, res: HttpResponse) -> HttpResponse {
+    let outcome = {
+        let etag = res.headers().get(ETAG).and_then(|v| v.to_str().ok());
+        let last_modified = res
+            .headers()
+            .get(LAST_MODIFIED)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| httpdate::parse_http_date(v).ok());
+        evaluate(method, conditions, etag, last_modified)
+    };
+
+    let (mut parts, _) = match outcome {
+        Outcome::Proceed => return res,
+        Outcome::NotModified => res.into_parts(),
+        Outcome::PreconditionFailed => {
+            let mut failed = HttpResponse::new(Body::empty());
+            *failed.status_mut() = StatusCode::PRECONDITION_FAILED;
+            for name in &[ETAG, LAST_MODIFIED] {
+                if let Some(value) = res.headers().get(name) {
+                    failed.headers_mut().insert(name, value.clone());
+                }
+            }
+            return failed;
+        }
+    };
+
+    parts.status = StatusCode::NOT_MODIFIED;
+    for name in &[
+        CONTENT_LENGTH,
+        CONTENT_RANGE,
+        CONTENT_TYPE,
+        TRANSFER_ENCODING,
+    ] {
+        parts.headers.remove(name);
+    }
+    HttpResponse::from_parts(parts, Body::empty())
+}
+
+#[derive(Debug, PartialEq)]
+enum Outcome {
+    Proceed,
+    NotModified,
+    PreconditionFailed,
+}
+
+/// Evaluates the preconditions in the order defined in RFC 7232, section 6.
+fn evaluate(
+    method: &Method,
+    headers: &HeaderMap,
+    etag: Option<&str>,
+    last_modified: Option<SystemTime>,
+) -> Outcome {
+    let safe = *method == Method::GET || *method == Method::HEAD;
+
+    if let Some(if_match) = header(headers, &IF_MATCH) {
+        if !matches(if_match, etag, true) {
+            return Outcome::PreconditionFailed;
+        }
+    } else if let Some(since) = header(headers, &IF_UNMODIFIED_SINCE) {
+        if let (Ok(since), Some(last_modified)) = (httpdate::parse_http_date(since), last_modified)
+        {
+            if truncate(last_modified) > since {
+                return Outcome::PreconditionFailed;
+            }
+        }
+    }
+
+    if let Some(if_none_match) = header(headers, &IF_NONE_MATCH) {
+        if matches(if_none_match, etag, false) {
+            return if safe {
+                Outcome::NotModified
+            } else {
+                Outcome::PreconditionFailed
+            };
+        }
+    } else if let Some(since) = header(headers, &IF_MODIFIED_SINCE) {
+        if let (true, Ok(since), Some(last_modified)) =
+            (safe, httpdate::parse_http_date(since), last_modified)
+        {
+            if truncate(last_modified) <= since {
+                return Outcome::NotModified;
+            }
+        }
+    }
+
+    Outcome::Proceed
+}
+
+fn header<'a>(headers: &'a HeaderMap, name: &HeaderName) -> Option<&'a str> {
+    headers.get(name).and_then(|v| v.to_str().ok())
+}
+
+/// Whether `etag` is contained in the list of entity tags `tags`. `If-Match` requires the strong
+/// comparison function, `If-None-Match` the weak one.
+fn matches(tags: &str, etag: Option<&str>, strong: bool) -> bool {
+    if tags.trim() == "*" {
+        return etag.is_some();
+    }
+    let etag = match etag {
+        Some(etag) if strong && etag.starts_with("W/") => return false,
+        Some(etag) => etag.trim_start_matches("W/"),
+        None => return false,
+    };
+    tags.split(',').map(str::trim).any(|tag| {
+        if strong && tag.starts_with("W/") {
+            false
+        } else {
+            tag.trim_start_matches("W/") == etag
+        }
+    })
+}
+
+/// HTTP dates have a resolution of one second.
+fn truncate(time: SystemTime) -> SystemTime {
+    match time.duration_since(SystemTime::UNIX_EPOCH) {
+        Ok(d) => SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(d.as_secs()),
+        Err(_) => time,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{evaluate, Outcome};
+    use crate::{
+        check_preconditions, conditional, default_fallback, App, HttpError, Request, Response,
+    };
+    use futures::{Future, Stream};
+    use hyper::header::{
+        HeaderMap, HeaderValue, CACHE_CONTROL, CONTENT_RANGE, ETAG, IF_MATCH, IF_MODIFIED_SINCE,
+        IF_NONE_MATCH, IF_UNMODIFIED_SINCE, LAST_MODIFIED,
+    };
+    use hyper::{Body, Method, StatusCode};
+    use std::time::{Duration, SystemTime};
+
+    fn execute(req: Request) -> crate::HttpResponse {
+        let mut app = App::new();
+        app.add(conditional());
+        app.add(|_, mut res: Response, _: (), _| {
+            res.header(LAST_MODIFIED, "Sun, 06 Nov 1994 08:49:37 GMT")
+                .body(Body::from("[\"a\",\"b\"]"))
+                .map_err(HttpError::Http)
+        });
+        app.build()
+            .execute(req, Response::new(), (), default_fallback)
+            .wait()
+            .unwrap()
+    }
+
+    #[test]
+    fn computes_etag() {
+        let res = execute(hyper::Request::get("/").body(Body::empty()).unwrap());
+        assert_eq!(res.status(), StatusCode::OK);
+        let etag = res.headers()[ETAG].clone();
+
+        let res = execute(hyper::Request::head("/").body(Body::empty()).unwrap());
+        assert_eq!(res.headers()[ETAG], etag);
+
+        let req = hyper::Request::get("/")
+            .header(IF_NONE_MATCH, etag.clone())
+            .body(Body::empty())
+            .unwrap();
+        let res = execute(req);
+        assert_eq!(res.status(), StatusCode::NOT_MODIFIED);
+        assert_eq!(res.headers()[ETAG], etag);
+    }
+
+    #[test]
+    fn skips_partial_responses() {
+        let mut app = App::new();
+        app.add(conditional());
+        app.add(|_, mut res: Response, _: (), _| {
+            res.status(StatusCode::PARTIAL_CONTENT)
+                .header(CONTENT_RANGE, "bytes 0-1/9")
+                .body(Body::from("[\""))
+                .map_err(HttpError::Http)
+        });
+        let res = app
+            .build()
+            .execute(
+                hyper::Request::get("/").body(Body::empty()).unwrap(),
+                Response::new(),
+                (),
+                default_fallback,
+            )
+            .wait()
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::PARTIAL_CONTENT);
+        assert!(!res.headers().contains_key(ETAG));
+    }
+
+    #[test]
+    fn if_match_mismatch() {
+        let req = hyper::Request::get("/")
+            .header(IF_MATCH, "\"other\"")
+            .body(Body::empty())
+            .unwrap();
+        let res = execute(req);
+        assert_eq!(res.status(), StatusCode::PRECONDITION_FAILED);
+        assert!(res.headers().contains_key(ETAG));
+        assert!(res.headers().contains_key(LAST_MODIFIED));
+    }
+
+    #[test]
+    fn checks_preconditions_in_handlers() {
+        let mut app = App::new();
+        app.add(|req: Request, mut res: Response, _: (), _| {
+            res.header(CACHE_CONTROL, "max-age=60");
+            check_preconditions(&req, &mut res, Some("\"1\""), None)?;
+            res.body(Body::from("current")).map_err(HttpError::Http)
+        });
+        let app = app.build();
+
+        for (method, status) in &[
+            (Method::GET, StatusCode::NOT_MODIFIED),
+            (Method::PUT, StatusCode::PRECONDITION_FAILED),
+        ] {
+            let req = hyper::Request::builder()
+                .method(method)
+                .header(IF_NONE_MATCH, "\"1\"")
+                .body(Body::empty())
+                .unwrap();
+            let res = match app
+                .execute(req, Response::new(), (), default_fallback)
+                .wait()
+            {
+                Err(HttpError::Response(res)) => res,
+                _ => panic!("expected a response error"),
+            };
+            assert_eq!(res.status(), *status);
+            assert_eq!(res.headers()[ETAG], "\"1\"");
+            assert_eq!(res.headers()[CACHE_CONTROL], "max-age=60");
+            assert!(res.into_body().concat2().wait().unwrap().is_empty());
+        }
+    }
+
+    #[test]
+    fn if_modified_since() {
+        let req = hyper::Request::get("/")
+            .header(IF_MODIFIED_SINCE, "Sun, 06 Nov 1994 08:49:37 GMT")
+            .body(Body::empty())
+            .unwrap();
+        assert_eq!(execute(req).status(), StatusCode::NOT_MODIFIED);
+
+        let req = hyper::Request::get("/")
+            .header(IF_MODIFIED_SINCE, "Sat, 05 Nov 1994 08:49:37 GMT")
+            .body(Body::empty())
+            .unwrap();
+        assert_eq!(execute(req).status(), StatusCode::OK);
+    }
+
+    #[test]
+    fn unsafe_methods() {
+        let mut headers = HeaderMap::new();
+        headers.insert(IF_NONE_MATCH, HeaderValue::from_static("*"));
+        assert_eq!(
+            evaluate(&Method::PUT, &headers, Some("\"1\""), None),
+            Outcome::PreconditionFailed
+        );
+        assert_eq!(
+            evaluate(&Method::PUT, &headers, None, None),
+            Outcome::Proceed
+        );
+
+        let mut headers = HeaderMap::new();
+        headers.insert(IF_MATCH, HeaderValue::from_static("W/\"1\""));
+        assert_eq!(
+            evaluate(&Method::PUT, &headers, Some("W/\"1\""), None),
+            Outcome::PreconditionFailed
+        );
+
+        let modified = SystemTime::UNIX_EPOCH + Duration::from_secs(784_111_777);
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            IF_UNMODIFIED_SINCE,
+            HeaderValue::from_static("Sun, 06 Nov 1994 08:49:36 GMT"),
+        );
+        assert_eq!(
+            evaluate(&Method::DELETE, &headers, None, Some(modified)),
+            Outcome::PreconditionFailed
+        );
+    }
+}
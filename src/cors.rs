@@ -0,0 +1,478 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::{
+    ErrorHeaders, HttpError, HttpResponse, Middleware, Next, Request, Response, ResponseFuture,
+};
+use futures::{future, Future};
+use hyper::header::{
+    HeaderMap, HeaderName, HeaderValue, ACCESS_CONTROL_ALLOW_CREDENTIALS,
+    ACCESS_CONTROL_ALLOW_HEADERS, ACCESS_CONTROL_ALLOW_METHODS, ACCESS_CONTROL_ALLOW_ORIGIN,
+    ACCESS_CONTROL_EXPOSE_HEADERS, ACCESS_CONTROL_MAX_AGE, ACCESS_CONTROL_REQUEST_HEADERS,
+    ACCESS_CONTROL_REQUEST_METHOD, ORIGIN, VARY,
+};
+use hyper::{Body, Method, StatusCode};
+#[cfg(feature = "regex")]
+use regex::Regex;
+
+#[derive(Clone)]
+enum AllowOrigin {
+    Exact(String),
+    Predicate(Arc<dyn Fn(&str) -> bool + Send + Sync>),
+    #[cfg(feature = "regex")]
+    Regex(Regex),
+}
+
+impl AllowOrigin {
+    fn matches(&self, origin: &str) -> bool {
+        match *self {
+            AllowOrigin::Exact(ref allowed) => allowed == origin,
+            AllowOrigin::Predicate(ref predicate) => predicate(origin),
+            #[cfg(feature = "regex")]
+            AllowOrigin::Regex(ref regex) => regex.is_match(origin),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct Cors {
+    origins: Vec<AllowOrigin>,
+    methods: Vec<Method>,
+    headers: Option<Vec<HeaderName>>,
+    expose_headers: Vec<HeaderName>,
+    credentials: bool,
+    max_age: Option<Duration>,
+}
+
+/// Handles cross-origin requests. Allows requests from any origin with the methods `GET`, `HEAD`,
+/// `POST`, `PUT`, `PATCH` and `DELETE` and any request header unless configured otherwise.
+/// Preflight requests are answered without calling the next middleware. The headers are added
+/// to responses rendered from errors as well, so that clients can read those.
+pub fn cors() -> Cors {
+    Cors {
+        origins: Vec::new(),
+        methods: vec![
+            Method::GET,
+            Method::HEAD,
+            Method::POST,
+            Method::PUT,
+            Method::PATCH,
+            Method::DELETE,
+        ],
+        headers: None,
+        expose_headers: Vec::new(),
+        credentials: false,
+        max_age: None,
+    }
+}
+
+impl Cors {
+    /// Only allows the given origins (and the ones added with the other `allow_origin` methods),
+    /// e.g. `https://example.com`.
+    pub fn allow_origins(mut self, origins: &[&str]) -> Self {
+        self.origins.extend(
+            origins
+                .iter()
+                .map(|origin| AllowOrigin::Exact((*origin).to_owned())),
+        );
+        self
+    }
+
+    /// Allows the origins `predicate` returns true for.
+    pub fn allow_origin_fn<F>(mut self, predicate: F) -> Self
+    where
+        F: Fn(&str) -> bool + Send + Sync + 'static,
+    {
+        self.origins
+            .push(AllowOrigin::Predicate(Arc::new(predicate)));
+        self
+    }
+
+    /// Allows the origins matching `regex`, which should be anchored, e.g.
+    /// `^https://[a-z]+\.example\.com$`.
+    #[cfg(feature = "regex")]
+    pub fn allow_origin_regex(mut self, regex: Regex) -> Self {
+        self.origins.push(AllowOrigin::Regex(regex));
+        self
+    }
+
+    pub fn allow_methods(mut self, methods: &[Method]) -> Self {
+        self.methods = methods.to_vec();
+        self
+    }
+
+    /// Only allows the given request headers, instead of any header the client asks for.
+    pub fn allow_headers(mut self, headers: &[HeaderName]) -> Self {
+        self.headers = Some(headers.to_vec());
+        self
+    }
+
+    /// Response headers, besides the CORS-safelisted ones, the client is allowed to read.
+    pub fn expose_headers(mut self, headers: &[HeaderName]) -> Self {
+        self.expose_headers = headers.to_vec();
+        self
+    }
+
+    /// Allows requests with cookies or HTTP authentication from the configured origins. Since any
+    /// origin allowed this way can read responses on behalf of the user, the origins have to be
+    /// configured explicitly: without any, all cross-origin requests are rejected. Browsers do
+    /// not accept `*` together with credentials, so the origin of the request is sent back instead.
+    pub fn allow_credentials(mut self, allow: bool) -> Self {
+        self.credentials = allow;
+        self
+    }
+
+    /// How long the result of a preflight request may be cached.
+    pub fn max_age(mut self, max_age: Duration) -> Self {
+        self.max_age = Some(max_age);
+        self
+    }
+
+    /// Whether `Access-Control-Allow-Origin` depends on the origin of the request.
+    fn varies(&self) -> bool {
+        !self.origins.is_empty()
+    }
+
+    fn allowed(&self, origin: &str) -> bool {
+        if self.origins.is_empty() {
+            !self.credentials
+        } else {
+            self.origins.iter().any(|allowed| allowed.matches(origin))
+        }
+    }
+
+    /// Adds the headers that apply to both, preflight and actual requests.
+    fn apply(&self, headers: &mut HeaderMap, origin: &HeaderValue) {
+        if self.varies() {
+            headers.insert(ACCESS_CONTROL_ALLOW_ORIGIN, origin.clone());
+        } else {
+            headers.insert(ACCESS_CONTROL_ALLOW_ORIGIN, HeaderValue::from_static("*"));
+        }
+        if self.credentials {
+            headers.insert(
+                ACCESS_CONTROL_ALLOW_CREDENTIALS,
+                HeaderValue::from_static("true"),
+            );
+        }
+    }
+
+    fn preflight(&self, req: &Request, origin: &HeaderValue) -> Result<HttpResponse, HttpError> {
+        let forbidden = || HttpError::Status(StatusCode::FORBIDDEN);
+
+        let method = req
+            .headers()
+            .get(ACCESS_CONTROL_REQUEST_METHOD)
+            .and_then(|v| Method::from_bytes(v.as_bytes()).ok())
+            .ok_or_else(forbidden)?;
+        if !self.methods.contains(&method) {
+            return Err(forbidden());
+        }
+
+        let requested = req
+            .headers()
+            .get_all(ACCESS_CONTROL_REQUEST_HEADERS)
+            .iter()
+            .filter_map(|v| v.to_str().ok())
+            .flat_map(|v| v.split(','))
+            .map(str::trim)
+            .filter(|name| !name.is_empty())
+            .map(|name| HeaderName::from_bytes(name.as_bytes()).map_err(|_| forbidden()))
+            .collect::<Result<Vec<_>, _>>()?;
+        if let Some(ref allowed) = self.headers {
+            if requested.iter().any(|name| !allowed.contains(name)) {
+                return Err(forbidden());
+            }
+        }
+
+        let mut res = HttpResponse::new(Body::empty());
+        *res.status_mut() = StatusCode::NO_CONTENT;
+        {
+            let headers = res.headers_mut();
+            self.apply(headers, origin);
+            headers.insert(
+                ACCESS_CONTROL_ALLOW_METHODS,
+                join(self.methods.iter().map(Method::as_str)),
+            );
+            let allow_headers = match self.headers {
+                Some(ref allowed) => join(allowed.iter().map(HeaderName::as_str)),
+                None => join(requested.iter().map(HeaderName::as_str)),
+            };
+            if !allow_headers.is_empty() {
+                headers.insert(ACCESS_CONTROL_ALLOW_HEADERS, allow_headers);
+            }
+            if let Some(max_age) = self.max_age {
+                headers.insert(ACCESS_CONTROL_MAX_AGE, max_age.as_secs().into());
+            }
+            if self.varies() {
+                headers.append(VARY, HeaderValue::from_static("Origin"));
+            }
+            if self.headers.is_none() {
+                headers.append(
+                    VARY,
+                    HeaderValue::from_static("Access-Control-Request-Headers"),
+                );
+            }
+        }
+        Ok(res)
+    }
+}
+
+fn join<'a, I: Iterator<Item = &'a str>>(values: I) -> HeaderValue {
+    HeaderValue::from_str(&values.collect::<Vec<_>>().join(", "))
+        .unwrap_or_else(|_| HeaderValue::from_static(""))
+}
+
+impl<S> Middleware<S> for Cors
+where
+    S: 'static,
+{
+    fn handle(&self, req: Request, res: Response, state: S, next: Next<S>) -> ResponseFuture {
+        let origin = req
+            .headers()
+            .get(ORIGIN)
+            .filter(|origin| origin.to_str().map(|o| self.allowed(o)).unwrap_or(false))
+            .cloned();
+        let is_preflight = req.method() == Method::OPTIONS
+            && req.headers().contains_key(ACCESS_CONTROL_REQUEST_METHOD);
+
+        if is_preflight {
+            let res = match origin {
+                Some(ref origin) => self.preflight(&req, origin),
+                None => Err(HttpError::Status(StatusCode::FORBIDDEN)),
+            };
+            return Box::new(future::result(res));
+        }
+
+        let errors = req.extensions().get::<ErrorHeaders>().cloned();
+        let config = self.clone();
+        Box::new(next(req, res, state).then(move |result| {
+            ErrorHeaders::add(errors.as_ref(), result, |headers| {
+                if let Some(ref origin) = origin {
+                    config.apply(headers, origin);
+                    if !config.expose_headers.is_empty() {
+                        headers.insert(
+                            ACCESS_CONTROL_EXPOSE_HEADERS,
+                            join(config.expose_headers.iter().map(HeaderName::as_str)),
+                        );
+                    }
+                }
+                // caches must not reuse a response for other origins, regardless of whether the
+                // current request had one
+                if config.varies() {
+                    headers.append(VARY, HeaderValue::from_static("Origin"));
+                }
+            })
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{cors, default_fallback, App, HttpError, HttpResponse, Request, Response};
+    use futures::Future;
+    use hyper::header::{
+        HeaderName, ACCESS_CONTROL_ALLOW_CREDENTIALS, ACCESS_CONTROL_ALLOW_HEADERS,
+        ACCESS_CONTROL_ALLOW_METHODS, ACCESS_CONTROL_ALLOW_ORIGIN, ACCESS_CONTROL_EXPOSE_HEADERS,
+        ACCESS_CONTROL_MAX_AGE, ACCESS_CONTROL_REQUEST_HEADERS, ACCESS_CONTROL_REQUEST_METHOD,
+        CONTENT_TYPE, ORIGIN, VARY,
+    };
+    use hyper::{Body, Method, StatusCode};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    fn execute(cors: crate::Cors, req: Request) -> (Result<HttpResponse, HttpError>, usize) {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let mut app = App::new();
+        app.add(cors);
+        {
+            let calls = calls.clone();
+            app.add(move |_, res: Response, _: (), _| {
+                calls.fetch_add(1, Ordering::SeqCst);
+                Ok::<_, HttpError>(res)
+            });
+        }
+        let res = app
+            .build()
+            .execute(req, Response::new(), (), default_fallback)
+            .wait();
+        (res, calls.load(Ordering::SeqCst))
+    }
+
+    #[test]
+    fn any_origin() {
+        let req = hyper::Request::get("/")
+            .header(ORIGIN, "https://example.com")
+            .body(Body::empty())
+            .unwrap();
+        let (res, calls) = execute(cors(), req);
+        let res = res.unwrap();
+        assert_eq!(calls, 1);
+        assert_eq!(res.headers()[ACCESS_CONTROL_ALLOW_ORIGIN], "*");
+        assert!(res.headers().get(VARY).is_none());
+    }
+
+    #[test]
+    fn preflight() {
+        let cors = cors()
+            .allow_origins(&["https://example.com"])
+            .allow_origin_fn(|origin| origin.ends_with(".example.com"))
+            .allow_methods(&[Method::GET, Method::PUT])
+            .allow_headers(&[CONTENT_TYPE])
+            .allow_credentials(true)
+            .max_age(Duration::from_secs(600));
+        let req = hyper::Request::options("/")
+            .header(ORIGIN, "https://api.example.com")
+            .header(ACCESS_CONTROL_REQUEST_METHOD, "PUT")
+            .header(ACCESS_CONTROL_REQUEST_HEADERS, "Content-Type")
+            .body(Body::empty())
+            .unwrap();
+        let (res, calls) = execute(cors.clone(), req);
+        let res = res.unwrap();
+
+        assert_eq!(calls, 0);
+        assert_eq!(res.status(), StatusCode::NO_CONTENT);
+        assert_eq!(
+            res.headers()[ACCESS_CONTROL_ALLOW_ORIGIN],
+            "https://api.example.com"
+        );
+        assert_eq!(res.headers()[ACCESS_CONTROL_ALLOW_METHODS], "GET, PUT");
+        assert_eq!(res.headers()[ACCESS_CONTROL_ALLOW_HEADERS], "content-type");
+        assert_eq!(res.headers()[ACCESS_CONTROL_ALLOW_CREDENTIALS], "true");
+        assert_eq!(res.headers()[ACCESS_CONTROL_MAX_AGE], "600");
+        assert_eq!(res.headers()[VARY], "Origin");
+
+        let req = hyper::Request::options("/")
+            .header(ORIGIN, "https://example.com")
+            .header(ACCESS_CONTROL_REQUEST_METHOD, "DELETE")
+            .body(Body::empty())
+            .unwrap();
+        match execute(cors, req).0 {
+            Err(HttpError::Status(status)) => assert_eq!(status, StatusCode::FORBIDDEN),
+            _ => panic!("expected forbidden"),
+        }
+    }
+
+    #[test]
+    fn credentials_require_explicit_origins() {
+        let req = hyper::Request::get("/")
+            .header(ORIGIN, "https://evil.example")
+            .body(Body::empty())
+            .unwrap();
+        let (res, calls) = execute(cors().allow_credentials(true), req);
+        let res = res.unwrap();
+        assert_eq!(calls, 1);
+        assert!(res.headers().get(ACCESS_CONTROL_ALLOW_ORIGIN).is_none());
+        assert!(res
+            .headers()
+            .get(ACCESS_CONTROL_ALLOW_CREDENTIALS)
+            .is_none());
+
+        let req = hyper::Request::options("/")
+            .header(ORIGIN, "https://evil.example")
+            .header(ACCESS_CONTROL_REQUEST_METHOD, "POST")
+            .body(Body::empty())
+            .unwrap();
+        match execute(cors().allow_credentials(true), req).0 {
+            Err(HttpError::Status(status)) => assert_eq!(status, StatusCode::FORBIDDEN),
+            _ => panic!("expected forbidden"),
+        }
+    }
+
+    #[test]
+    fn mirrors_requested_headers() {
+        let req = hyper::Request::options("/")
+            .header(ORIGIN, "https://example.com")
+            .header(ACCESS_CONTROL_REQUEST_METHOD, "POST")
+            .header(ACCESS_CONTROL_REQUEST_HEADERS, "x-custom, content-type")
+            .body(Body::empty())
+            .unwrap();
+        let res = execute(cors(), req).0.unwrap();
+        assert_eq!(
+            res.headers()[ACCESS_CONTROL_ALLOW_HEADERS],
+            "x-custom, content-type"
+        );
+        assert_eq!(res.headers()[VARY], "Access-Control-Request-Headers");
+    }
+
+    #[test]
+    fn disallowed_origin() {
+        let cors = cors()
+            .allow_origins(&["https://example.com"])
+            .expose_headers(&[HeaderName::from_static("x-total")]);
+
+        let req = hyper::Request::get("/")
+            .header(ORIGIN, "https://evil.com")
+            .body(Body::empty())
+            .unwrap();
+        let (res, calls) = execute(cors.clone(), req);
+        let res = res.unwrap();
+        assert_eq!(calls, 1);
+        assert!(res.headers().get(ACCESS_CONTROL_ALLOW_ORIGIN).is_none());
+        assert_eq!(res.headers()[VARY], "Origin");
+
+        let req = hyper::Request::get("/")
+            .header(ORIGIN, "https://example.com")
+            .body(Body::empty())
+            .unwrap();
+        let res = execute(cors, req).0.unwrap();
+        assert_eq!(
+            res.headers()[ACCESS_CONTROL_ALLOW_ORIGIN],
+            "https://example.com"
+        );
+        assert_eq!(res.headers()[ACCESS_CONTROL_EXPOSE_HEADERS], "x-total");
+    }
+
+    #[test]
+    fn adds_headers_to_errors() {
+        use hyper::service::Service;
+
+        let mut app = App::new();
+        app.add(cors().allow_origins(&["https://example.com"]));
+        app.add(|req: Request, _res, _state, _next: crate::Next<()>| {
+            if req.uri().path() == "/response" {
+                let res = hyper::Response::builder()
+                    .status(StatusCode::CONFLICT)
+                    .body(Body::empty())
+                    .unwrap();
+                Err::<HttpResponse, _>(HttpError::Response(res))
+            } else {
+                Err(HttpError::Status(StatusCode::UNAUTHORIZED))
+            }
+        });
+        let mut server = app.build().serve(|| ());
+
+        for (path, status) in &[
+            ("/response", StatusCode::CONFLICT),
+            ("/status", StatusCode::UNAUTHORIZED),
+        ] {
+            let req = hyper::Request::get(format!("http://localhost{}", path))
+                .header(ORIGIN, "https://example.com")
+                .body(Body::empty())
+                .unwrap();
+            let res = server.call(req).wait().unwrap();
+            assert_eq!(res.status(), *status);
+            assert_eq!(
+                res.headers()[ACCESS_CONTROL_ALLOW_ORIGIN],
+                "https://example.com"
+            );
+            assert_eq!(res.headers()[VARY], "Origin");
+        }
+    }
+
+    #[cfg(feature = "regex")]
+    #[test]
+    fn regex_origin() {
+        let cors = cors()
+            .allow_origin_regex(regex::Regex::new(r"^https://[a-z]+\.example\.com$").unwrap());
+        let req = hyper::Request::get("/")
+            .header(ORIGIN, "https://app.example.com")
+            .body(Body::empty())
+            .unwrap();
+        let res = execute(cors, req).0.unwrap();
+        assert_eq!(
+            res.headers()[ACCESS_CONTROL_ALLOW_ORIGIN],
+            "https://app.example.com"
+        );
+    }
+}
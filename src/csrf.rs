@@ -0,0 +1,698 @@
+use std::sync::{Arc, Mutex};
+
+use crate::session::generate_id;
+use crate::{
+    HttpError, HttpResponse, Middleware, Next, Request, Response, ResponseFuture, SameSite, Session,
+};
+use cookie::{Cookie, CookieJar, Key};
+use futures::future::{self, Either, Future};
+use futures::Stream;
+use hyper::header::{
+    HeaderName, HeaderValue, CONTENT_TYPE, COOKIE, HOST, ORIGIN, REFERER, SET_COOKIE,
+};
+use hyper::{Body, Method, StatusCode};
+use percent_encoding::percent_decode;
+
+/// The key of the token within the session.
+const SESSION_KEY: &str = "_csrf";
+
+/// The CSRF token of the current request, available through the request extensions to be
+/// rendered into forms. A new token is only generated, and stored in the session or cookie, once
+/// it is read.
+#[derive(Clone)]
+pub struct CsrfToken(Arc<Mutex<Source>>);
+
+enum Source {
+    Session(Session),
+    /// The token from the cookie, or the one generated for a new cookie.
+    Cookie {
+        token: Option<String>,
+        generated: bool,
+    },
+}
+
+impl CsrfToken {
+    pub fn value(&self) -> Result<String, HttpError> {
+        match *self.0.lock().unwrap() {
+            Source::Session(ref session) => match session.get::<String>(SESSION_KEY) {
+                Some(token) => Ok(token),
+                None => {
+                    let token = generate_id();
+                    session.set(SESSION_KEY, &token)?;
+                    Ok(token)
+                }
+            },
+            Source::Cookie {
+                ref mut token,
+                ref mut generated,
+            } => Ok(token
+                .get_or_insert_with(|| {
+                    *generated = true;
+                    generate_id()
+                })
+                .clone()),
+        }
+    }
+
+    /// The token that has been generated for a new cookie, if any.
+    fn generated(&self) -> Option<String> {
+        match *self.0.lock().unwrap() {
+            Source::Cookie {
+                ref token,
+                generated: true,
+            } => token.clone(),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone)]
+enum Strategy {
+    Synchronizer,
+    DoubleSubmit(Key),
+}
+
+#[derive(Clone)]
+pub struct Csrf {
+    strategy: Strategy,
+    header: HeaderName,
+    field: String,
+    cookie_name: String,
+    secure: bool,
+    exempt: Arc<Vec<String>>,
+    trusted_origins: Arc<Vec<String>>,
+    max_form_size: usize,
+}
+
+/// Protects against cross-site request forgery with a token that has to be sent along with every
+/// request that is not `GET`, `HEAD`, `OPTIONS` or `TRACE`, either in the `X-CSRF-Token` header
+/// or in the `csrf_token` field of a URL encoded form. Requests with a missing or wrong token, or
+/// with an `Origin` or `Referer` of another scheme or host, are answered with `403 Forbidden`.
+///
+/// The token is kept in the session by default, so `sessions` has to be added before. Use
+/// `double_submit` to keep it in a cookie instead.
+pub fn csrf() -> Csrf {
+    Csrf {
+        strategy: Strategy::Synchronizer,
+        header: HeaderName::from_static("x-csrf-token"),
+        field: "csrf_token".to_owned(),
+        cookie_name: "csrf".to_owned(),
+        secure: true,
+        exempt: Arc::new(Vec::new()),
+        trusted_origins: Arc::new(Vec::new()),
+        max_form_size: 1024 * 1024,
+    }
+}
+
+impl Csrf {
+    /// Keeps the token in a cookie and compares it with the one sent with the request, which
+    /// does not require a session. The cookie is signed with a key derived from `secret`, so that
+    /// it cannot be set by e.g. a sibling subdomain. Panics if `secret` is shorter than 32 bytes.
+    pub fn double_submit(mut self, secret: &[u8]) -> Self {
+        self.strategy = Strategy::DoubleSubmit(Key::derive_from(secret));
+        self
+    }
+
+    pub fn header(mut self, name: HeaderName) -> Self {
+        self.header = name;
+        self
+    }
+
+    pub fn field(mut self, name: &str) -> Self {
+        self.field = name.to_owned();
+        self
+    }
+
+    /// The name of the cookie used by `double_submit` (defaults to `csrf`).
+    pub fn cookie_name(mut self, name: &str) -> Self {
+        self.cookie_name = name.to_owned();
+        self
+    }
+
+    /// Whether the site is served over HTTPS (defaults to true), which is required of the
+    /// `Origin` and `Referer` and makes the cookie used by `double_submit` `Secure`.
+    pub fn secure(mut self, secure: bool) -> Self {
+        self.secure = secure;
+        self
+    }
+
+    /// Skips the checks for requests below `path`, e.g. for webhooks that are authenticated
+    /// otherwise.
+    pub fn exempt(mut self, path: &str) -> Self {
+        Arc::make_mut(&mut self.exempt).push(path.trim_end_matches('/').to_owned());
+        self
+    }
+
+    /// Also accepts requests from `origin`, e.g. `https://app.example.com`.
+    pub fn trusted_origin(mut self, origin: &str) -> Self {
+        Arc::make_mut(&mut self.trusted_origins).push(origin.to_owned());
+        self
+    }
+
+    /// The maximum size of form bodies searched for the token (defaults to 1 MB).
+    pub fn max_form_size(mut self, size: usize) -> Self {
+        self.max_form_size = size;
+        self
+    }
+
+    fn exempted(&self, req: &Request) -> bool {
+        let path = req.uri().path();
+        self.exempt.iter().any(|prefix| {
+            path.starts_with(prefix.as_str())
+                && (path.len() == prefix.len() || path[prefix.len()..].starts_with('/'))
+        })
+    }
+
+    /// Whether the `Origin`, or if missing the `Referer`, is the scheme and host of the request or
+    /// trusted. Requests without both are left to the token check.
+    fn same_origin(&self, req: &Request) -> bool {
+        let source = match req
+            .headers()
+            .get(ORIGIN)
+            .or_else(|| req.headers().get(REFERER))
+        {
+            Some(source) => source.to_str().unwrap_or("null"),
+            None => return true,
+        };
+        let origin = match origin_of(source) {
+            Some(origin) => origin,
+            None => return false,
+        };
+        if self.trusted_origins.iter().any(|trusted| trusted == origin) {
+            return true;
+        }
+        let host = req
+            .headers()
+            .get(HOST)
+            .and_then(|host| host.to_str().ok())
+            .or_else(|| {
+                req.uri()
+                    .authority_part()
+                    .map(|authority| authority.as_str())
+            });
+        let scheme = req
+            .uri()
+            .scheme_str()
+            .unwrap_or(if self.secure { "https" } else { "http" });
+        match (host, origin.find("://")) {
+            (Some(host), Some(pos)) => {
+                origin[..pos].eq_ignore_ascii_case(scheme)
+                    && origin[pos + 3..].eq_ignore_ascii_case(host)
+            }
+            _ => false,
+        }
+    }
+
+    /// The token of the cookie, if its signature is valid.
+    fn cookie(&self, req: &Request, key: &Key) -> Option<String> {
+        req.headers()
+            .get_all(COOKIE)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(';'))
+            .filter_map(|pair| {
+                let mut parts = pair.splitn(2, '=');
+                match (parts.next(), parts.next()) {
+                    (Some(name), Some(value)) if name.trim() == self.cookie_name => {
+                        Some(value.trim().to_owned())
+                    }
+                    _ => None,
+                }
+            })
+            .filter_map(|value| {
+                CookieJar::new()
+                    .signed(key)
+                    .verify(Cookie::new(self.cookie_name.clone(), value))
+            })
+            .map(|cookie| cookie.value().to_owned())
+            .find(|value| valid_token(value))
+    }
+
+    fn set_cookie(&self, res: &mut HttpResponse, key: &Key, token: &str) {
+        let mut jar = CookieJar::new();
+        jar.signed_mut(key).add(
+            Cookie::build(self.cookie_name.clone(), token.to_owned())
+                .path("/")
+                .secure(self.secure)
+                .same_site(SameSite::Strict)
+                .finish(),
+        );
+        let cookie = jar.get(&self.cookie_name).map(ToString::to_string);
+        if let Some(Ok(value)) = cookie.map(|cookie| HeaderValue::from_str(&cookie)) {
+            res.headers_mut().append(SET_COOKIE, value);
+        }
+    }
+
+    /// Resolves to the request, with its body restored if it had to be read to find the token.
+    fn check(
+        &self,
+        req: Request,
+        expected: &str,
+    ) -> Box<dyn Future<Item = Request, Error = HttpError> + Send> {
+        if let Some(token) = req.headers().get(&self.header) {
+            return Box::new(future::result(if matches(token.as_bytes(), expected) {
+                Ok(req)
+            } else {
+                Err(forbidden())
+            }));
+        }
+
+        let is_form = req
+            .headers()
+            .get(CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .is_some_and(|value| value.starts_with("application/x-www-form-urlencoded"));
+        if !is_form {
+            return Box::new(future::err(forbidden()));
+        }
+
+        let (parts, body) = req.into_parts();
+        let field = self.field.clone();
+        let expected = expected.to_owned();
+        let max_size = self.max_form_size;
+        Box::new(
+            body.map_err(|_| HttpError::Status(StatusCode::BAD_REQUEST))
+                .fold(Vec::new(), move |mut body, chunk| {
+                    body.extend_from_slice(&chunk);
+                    if body.len() > max_size {
+                        Err(HttpError::Status(StatusCode::PAYLOAD_TOO_LARGE))
+                    } else {
+                        Ok(body)
+                    }
+                })
+                .and_then(move |body| match form_field(&body, &field) {
+                    Some(ref token) if matches(token.as_bytes(), &expected) => {
+                        Ok(Request::from_parts(parts, Body::from(body)))
+                    }
+                    _ => Err(forbidden()),
+                }),
+        )
+    }
+}
+
+impl<S> Middleware<S> for Csrf
+where
+    S: Send + 'static,
+{
+    fn handle(&self, mut req: Request, res: Response, state: S, next: Next<S>) -> ResponseFuture {
+        let (expected, source) = match self.strategy {
+            Strategy::Synchronizer => match req.extensions().get::<Session>() {
+                Some(session) => {
+                    // a new token is generated after login
+                    session.on_regenerate(|session| session.remove(SESSION_KEY));
+                    (
+                        session.get::<String>(SESSION_KEY),
+                        Source::Session(session.clone()),
+                    )
+                }
+                None => {
+                    return Box::new(future::err(HttpError::internal(
+                        "the csrf middleware requires the sessions middleware",
+                    )))
+                }
+            },
+            Strategy::DoubleSubmit(ref key) => {
+                let token = self.cookie(&req, key);
+                let source = Source::Cookie {
+                    token: token.clone(),
+                    generated: false,
+                };
+                (token, source)
+            }
+        };
+        let token = CsrfToken(Arc::new(Mutex::new(source)));
+        req.extensions_mut().insert(token.clone());
+
+        let checked = if safe(req.method()) || self.exempted(&req) {
+            Either::A(future::ok(req))
+        } else if !self.same_origin(&req) {
+            Either::A(future::err(forbidden()))
+        } else {
+            match expected {
+                Some(ref expected) => Either::B(self.check(req, expected)),
+                None => Either::A(future::err(forbidden())),
+            }
+        };
+
+        let config = self.clone();
+        Box::new(checked.and_then(move |req| {
+            next(req, res, state).map(move |mut res| {
+                if let Strategy::DoubleSubmit(ref key) = config.strategy {
+                    if let Some(token) = token.generated() {
+                        config.set_cookie(&mut res, key, &token);
+                    }
+                }
+                res
+            })
+        }))
+    }
+}
+
+fn safe(method: &Method) -> bool {
+    *method == Method::GET
+        || *method == Method::HEAD
+        || *method == Method::OPTIONS
+        || *method == Method::TRACE
+}
+
+fn valid_token(token: &str) -> bool {
+    token.len() == 64 && token.bytes().all(|b| b.is_ascii_hexdigit())
+}
+
+/// Compares in constant time, so that the token cannot be guessed from response times.
+fn matches(token: &[u8], expected: &str) -> bool {
+    let expected = expected.as_bytes();
+    token.len() == expected.len()
+        && token
+            .iter()
+            .zip(expected)
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+/// The `scheme://host[:port]` part of an `Origin` or `Referer`.
+fn origin_of(url: &str) -> Option<&str> {
+    let start = url.find("://")? + 3;
+    let end = url[start..]
+        .find(['/', '?', '#'])
+        .map_or(url.len(), |end| start + end);
+    if end == start {
+        None
+    } else {
+        Some(&url[..end])
+    }
+}
+
+fn form_field(body: &[u8], name: &str) -> Option<String> {
+    body.split(|b| *b == b'&').find_map(|pair| {
+        let mut parts = pair.splitn(2, |b| *b == b'=');
+        if decode(parts.next()?)? == name {
+            decode(parts.next().unwrap_or_default())
+        } else {
+            None
+        }
+    })
+}
+
+fn decode(component: &[u8]) -> Option<String> {
+    let component = component
+        .iter()
+        .map(|b| if *b == b'+' { b' ' } else { *b })
+        .collect::<Vec<_>>();
+    percent_decode(&component)
+        .decode_utf8()
+        .ok()
+        .map(|s| s.into_owned())
+}
+
+fn forbidden() -> HttpError {
+    HttpError::Status(StatusCode::FORBIDDEN)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{form_field, origin_of, CsrfToken};
+    use crate::{
+        csrf, default_fallback, sessions, App, Csrf, HttpError, HttpResponse, MemorySessionStore,
+        Next, Request, Response, Session,
+    };
+    use futures::{Future, Stream};
+    use hyper::header::{CONTENT_TYPE, COOKIE, HOST, ORIGIN, REFERER, SET_COOKIE};
+    use hyper::{Body, StatusCode};
+
+    fn app(csrf: Csrf, with_sessions: bool) -> App<()> {
+        let mut app = App::new();
+        if with_sessions {
+            app.add(sessions(MemorySessionStore::new()).secure(false));
+        }
+        app.add(csrf.secure(false).exempt("/hooks"));
+        app.add(|req: Request, mut res: Response, _state, _next: Next<()>| {
+            match req.uri().path() {
+                // renders a form
+                "/" => {
+                    let token = req.extensions().get::<CsrfToken>().unwrap().value();
+                    res.header("x-token", token.unwrap().as_str());
+                }
+                "/login" => req.extensions().get::<Session>().unwrap().regenerate(),
+                _ => {}
+            }
+            req.into_body()
+                .concat2()
+                .map_err(|_| HttpError::Status(StatusCode::BAD_REQUEST))
+                .and_then(move |body| res.body(Body::from(body)).map_err(HttpError::Http))
+        });
+        app.build()
+    }
+
+    fn call(app: &App<()>, req: hyper::Request<Body>) -> Result<HttpResponse, HttpError> {
+        app.execute(req, Response::new(), (), default_fallback)
+            .wait()
+    }
+
+    fn status(result: Result<HttpResponse, HttpError>) -> StatusCode {
+        match result {
+            Ok(res) => res.status(),
+            Err(err) => err.status(),
+        }
+    }
+
+    /// The cookies set by the response, sent back as a `Cookie` header.
+    fn cookies(res: &HttpResponse) -> String {
+        res.headers()
+            .get_all(SET_COOKIE)
+            .iter()
+            .map(|value| {
+                let value = value.to_str().unwrap();
+                value[..value.find(';').unwrap()].to_owned()
+            })
+            .collect::<Vec<_>>()
+            .join("; ")
+    }
+
+    fn post(cookies: &str) -> http::request::Builder {
+        let mut req = hyper::Request::post("/form");
+        req.header(HOST, "example.com").header(COOKIE, cookies);
+        req
+    }
+
+    #[test]
+    fn synchronizer_tokens() {
+        let app = app(csrf(), true);
+        let res = call(&app, hyper::Request::get("/").body(Body::empty()).unwrap()).unwrap();
+        let token = res.headers()["x-token"].to_str().unwrap().to_owned();
+        let cookies = cookies(&res);
+        assert!(cookies.starts_with("session="));
+
+        // the token stays the same for the session
+        let res = call(
+            &app,
+            hyper::Request::get("/")
+                .header(COOKIE, cookies.as_str())
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .unwrap();
+        assert_eq!(res.headers()["x-token"], token.as_str());
+
+        let res = call(
+            &app,
+            post(&cookies)
+                .header("x-csrf-token", token.as_str())
+                .body(Body::empty())
+                .unwrap(),
+        );
+        assert_eq!(status(res), StatusCode::OK);
+
+        // the form body is still available after the token has been read from it
+        let form = format!("name=a+b&csrf_token={}", token);
+        let res = call(
+            &app,
+            post(&cookies)
+                .header(CONTENT_TYPE, "application/x-www-form-urlencoded")
+                .header(ORIGIN, "http://example.com")
+                .body(Body::from(form.clone()))
+                .unwrap(),
+        )
+        .unwrap();
+        let body = res.into_body().concat2().wait().unwrap();
+        assert_eq!(&body[..], form.as_bytes());
+
+        for req in [
+            post(&cookies).body(Body::empty()).unwrap(),
+            post(&cookies)
+                .header("x-csrf-token", "wrong")
+                .body(Body::empty())
+                .unwrap(),
+            post(&cookies)
+                .header(CONTENT_TYPE, "application/x-www-form-urlencoded")
+                .body(Body::from("csrf_token=wrong"))
+                .unwrap(),
+            post(&cookies)
+                .header("x-csrf-token", token.as_str())
+                .header(ORIGIN, "https://evil.com")
+                .body(Body::empty())
+                .unwrap(),
+            post(&cookies)
+                .header("x-csrf-token", token.as_str())
+                .header(REFERER, "https://evil.com/example.com")
+                .body(Body::empty())
+                .unwrap(),
+            // the token of another session
+            post("")
+                .header("x-csrf-token", token.as_str())
+                .body(Body::empty())
+                .unwrap(),
+        ] {
+            assert_eq!(status(call(&app, req)), StatusCode::FORBIDDEN);
+        }
+
+        // exempted
+        let req = hyper::Request::post("/hooks/github")
+            .body(Body::empty())
+            .unwrap();
+        assert_eq!(status(call(&app, req)), StatusCode::OK);
+        let req = hyper::Request::post("/hooksy").body(Body::empty()).unwrap();
+        assert_eq!(status(call(&app, req)), StatusCode::FORBIDDEN);
+    }
+
+    #[test]
+    fn generates_tokens_lazily() {
+        for csrf in [csrf(), csrf().double_submit(&[7; 32])] {
+            let app = app(csrf, true);
+            for req in [
+                hyper::Request::get("/page").body(Body::empty()).unwrap(),
+                hyper::Request::post("/hooks/github")
+                    .body(Body::empty())
+                    .unwrap(),
+            ] {
+                let res = call(&app, req).unwrap();
+                assert_eq!(res.status(), StatusCode::OK);
+                assert!(res.headers().get(SET_COOKIE).is_none());
+            }
+        }
+    }
+
+    #[test]
+    fn replaces_tokens_on_login() {
+        let app = app(csrf(), true);
+        let res = call(&app, hyper::Request::get("/").body(Body::empty()).unwrap()).unwrap();
+        let token = res.headers()["x-token"].to_str().unwrap().to_owned();
+        let res = call(
+            &app,
+            hyper::Request::post("/login")
+                .header(COOKIE, cookies(&res).as_str())
+                .header("x-csrf-token", token.as_str())
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .unwrap();
+        let cookies = cookies(&res);
+
+        let res = call(
+            &app,
+            hyper::Request::get("/")
+                .header(COOKIE, cookies.as_str())
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .unwrap();
+        assert_ne!(res.headers()["x-token"], token.as_str());
+        let req = post(&cookies)
+            .header("x-csrf-token", token.as_str())
+            .body(Body::empty())
+            .unwrap();
+        assert_eq!(status(call(&app, req)), StatusCode::FORBIDDEN);
+    }
+
+    #[test]
+    fn compares_schemes() {
+        let app = app(csrf(), true);
+        let res = call(&app, hyper::Request::get("/").body(Body::empty()).unwrap()).unwrap();
+        let token = res.headers()["x-token"].to_str().unwrap().to_owned();
+        let cookies = cookies(&res);
+        for (origin, expected) in [
+            ("http://example.com", StatusCode::OK),
+            ("https://example.com", StatusCode::FORBIDDEN),
+        ] {
+            let req = post(&cookies)
+                .header("x-csrf-token", token.as_str())
+                .header(ORIGIN, origin)
+                .body(Body::empty())
+                .unwrap();
+            assert_eq!(status(call(&app, req)), expected);
+        }
+    }
+
+    #[test]
+    fn requires_sessions() {
+        let app = app(csrf(), false);
+        let res = call(&app, hyper::Request::get("/").body(Body::empty()).unwrap());
+        assert_eq!(status(res), StatusCode::INTERNAL_SERVER_ERROR);
+    }
+
+    #[test]
+    fn double_submit_cookies() {
+        let app = app(
+            csrf()
+                .double_submit(&[7; 32])
+                .trusted_origin("https://app.example.com"),
+            false,
+        );
+        let res = call(&app, hyper::Request::get("/").body(Body::empty()).unwrap()).unwrap();
+        let token = res.headers()["x-token"].to_str().unwrap().to_owned();
+        let cookies = cookies(&res);
+        assert!(cookies.starts_with("csrf=") && cookies.ends_with(&token));
+        assert_ne!(cookies, format!("csrf={}", token));
+
+        let res = call(
+            &app,
+            post(&cookies)
+                .header("x-csrf-token", token.as_str())
+                .header(ORIGIN, "https://app.example.com")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        // the cookie is only set once
+        assert!(res.headers().get(SET_COOKIE).is_none());
+
+        // missing, unsigned and otherwise signed cookies
+        let other = call(
+            &self::app(csrf().double_submit(&[8; 32]), false),
+            hyper::Request::get("/").body(Body::empty()).unwrap(),
+        )
+        .unwrap();
+        let other_token = other.headers()["x-token"].to_str().unwrap().to_owned();
+        for (cookies, token) in [
+            (String::new(), token.clone()),
+            (format!("csrf={}", token), token.clone()),
+            (self::cookies(&other), other_token),
+        ] {
+            let req = post(&cookies)
+                .header("x-csrf-token", token.as_str())
+                .body(Body::empty())
+                .unwrap();
+            assert_eq!(status(call(&app, req)), StatusCode::FORBIDDEN);
+        }
+    }
+
+    #[test]
+    fn parsing() {
+        assert_eq!(
+            origin_of("https://example.com:8443/path?q"),
+            Some("https://example.com:8443")
+        );
+        assert_eq!(
+            origin_of("https://example.com"),
+            Some("https://example.com")
+        );
+        assert_eq!(origin_of("null"), None);
+
+        assert_eq!(
+            form_field(b"a=1&csrf+token=x%2By&b", "csrf token"),
+            Some("x+y".to_owned())
+        );
+        assert_eq!(form_field(b"a=1", "csrf_token"), None);
+    }
+}
@@ -0,0 +1,336 @@
+use std::error::Error as StdError;
+use std::fmt;
+use std::io::{self, Write};
+use std::mem;
+use std::sync::{Arc, Mutex};
+
+use crate::{HttpError, Middleware, Next, Request, Response, ResponseFuture};
+use brotli::DecompressorWriter;
+use flate2::write::{GzDecoder, ZlibDecoder};
+use futures::{Async, Future, Poll, Stream};
+use hyper::header::{HeaderValue, ACCEPT_ENCODING, CONTENT_ENCODING, CONTENT_LENGTH};
+use hyper::{Body, Chunk, StatusCode};
+
+#[derive(Clone)]
+pub struct Decompression {
+    max_size: u64,
+}
+
+/// Decodes `gzip`, `deflate` and `br` encoded request bodies while they are read. Requests with
+/// other encodings are rejected with `415 Unsupported Media Type`.
+pub fn decompression() -> Decompression {
+    Decompression {
+        max_size: 10 * 1024 * 1024,
+    }
+}
+
+impl Decompression {
+    /// Reject request bodies that decompress to more than `bytes` with
+    /// `413 Payload Too Large` (defaults to 10 MiB).
+    pub fn max_size(mut self, bytes: u64) -> Self {
+        self.max_size = bytes;
+        self
+    }
+}
+
+impl<S> Middleware<S> for Decompression
+where
+    S: 'static,
+{
+    fn handle(&self, req: Request, mut res: Response, state: S, next: Next<S>) -> ResponseFuture {
+        let decoder = match req
+            .headers()
+            .get(CONTENT_ENCODING)
+            .map(|encoding| Decoder::new(encoding, self.max_size))
+        {
+            Some(Some(Some(decoder))) => decoder,
+            None | Some(Some(None)) => return next(req, res, state),
+            Some(None) => {
+                res.status(StatusCode::UNSUPPORTED_MEDIA_TYPE).header(
+                    ACCEPT_ENCODING,
+                    HeaderValue::from_static("gzip, deflate, br"),
+                );
+                return Box::new(futures::future::result(
+                    res.body(Body::empty()).map_err(HttpError::Http),
+                ));
+            }
+        };
+
+        let (mut parts, body) = req.into_parts();
+        parts.headers.remove(CONTENT_ENCODING);
+        parts.headers.remove(CONTENT_LENGTH);
+
+        // errors while reading the body surface in the handler, remember them to respond with
+        // the appropriate status code regardless of how the handler dealt with them
+        let failure = Arc::new(Mutex::new(None));
+        let stream = Decompress {
+            body,
+            decoder: Some(decoder),
+            failure: failure.clone(),
+        };
+        let req = Request::from_parts(parts, Body::wrap_stream(stream));
+
+        Box::new(
+            next(req, res, state).then(move |result| match failure.lock().unwrap().take() {
+                Some(status) => Err(HttpError::Status(status)),
+                None => result,
+            }),
+        )
+    }
+}
+
+/// Collects the decoded output and fails as soon as more than `max_size` bytes have been written
+/// in total, so that the decoders never produce much more than that.
+struct Output {
+    buf: Vec<u8>,
+    size: u64,
+    max_size: u64,
+}
+
+impl Write for Output {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        self.size += data.len() as u64;
+        if self.size > self.max_size {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, TooLarge));
+        }
+        self.buf.extend_from_slice(data);
+        Ok(data.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+struct TooLarge;
+
+impl fmt::Display for TooLarge {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("request body too large")
+    }
+}
+
+impl StdError for TooLarge {}
+
+enum Decoder {
+    Brotli(Box<DecompressorWriter<Output>>),
+    Gzip(GzDecoder<Output>),
+    Deflate(ZlibDecoder<Output>),
+}
+
+impl Decoder {
+    /// Returns `None` for unsupported encodings and `Some(None)` for `identity`.
+    fn new(encoding: &HeaderValue, max_size: u64) -> Option<Option<Self>> {
+        let encoding = encoding.to_str().ok()?.trim();
+        let output = Output {
+            buf: Vec::new(),
+            size: 0,
+            max_size,
+        };
+        let decoder =
+            if encoding.eq_ignore_ascii_case("gzip") || encoding.eq_ignore_ascii_case("x-gzip") {
+                Decoder::Gzip(GzDecoder::new(output))
+            } else if encoding.eq_ignore_ascii_case("deflate") {
+                Decoder::Deflate(ZlibDecoder::new(output))
+            } else if encoding.eq_ignore_ascii_case("br") {
+                Decoder::Brotli(Box::new(DecompressorWriter::new(output, 4096)))
+            } else if encoding.eq_ignore_ascii_case("identity") {
+                return Some(None);
+            } else {
+                return None;
+            };
+        Some(Some(decoder))
+    }
+
+    /// Decodes `data` and returns everything that has been decoded so far.
+    fn write(&mut self, data: &[u8]) -> io::Result<Vec<u8>> {
+        let output = match self {
+            Decoder::Brotli(w) => {
+                w.write_all(data)?;
+                w.get_mut()
+            }
+            Decoder::Gzip(w) => {
+                w.write_all(data)?;
+                w.get_mut()
+            }
+            Decoder::Deflate(w) => {
+                w.write_all(data)?;
+                w.get_mut()
+            }
+        };
+        Ok(mem::take(&mut output.buf))
+    }
+
+    fn finish(self) -> io::Result<Vec<u8>> {
+        let output = match self {
+            Decoder::Brotli(mut w) => {
+                w.close()?;
+                mem::take(&mut w.get_mut().buf)
+            }
+            Decoder::Gzip(w) => w.finish()?.buf,
+            Decoder::Deflate(w) => w.finish()?.buf,
+        };
+        Ok(output)
+    }
+}
+
+struct Decompress {
+    body: Body,
+    decoder: Option<Decoder>,
+    failure: Arc<Mutex<Option<StatusCode>>>,
+}
+
+impl Decompress {
+    fn fail(&mut self, err: io::Error) -> Box<dyn StdError + Send + Sync> {
+        let too_large = err.get_ref().is_some_and(|err| err.is::<TooLarge>());
+        self.decoder = None;
+        *self.failure.lock().unwrap() = Some(if too_large {
+            StatusCode::PAYLOAD_TOO_LARGE
+        } else {
+            StatusCode::BAD_REQUEST
+        });
+        Box::new(err)
+    }
+}
+
+impl Stream for Decompress {
+    type Item = Chunk;
+    type Error = Box<dyn StdError + Send + Sync>;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        loop {
+            let chunk = match self.decoder {
+                Some(_) => futures::try_ready!(self.body.poll()),
+                None => return Ok(Async::Ready(None)),
+            };
+
+            let out = match chunk {
+                Some(chunk) => self.decoder.as_mut().unwrap().write(&chunk),
+                None => self.decoder.take().unwrap().finish(),
+            };
+            match out {
+                Ok(ref out) if out.is_empty() => {}
+                Ok(out) => return Ok(Async::Ready(Some(out.into()))),
+                Err(err) => return Err(self.fail(err)),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Decoder, TooLarge};
+    use crate::{decompression, default_fallback, App, HttpError, Request, Response};
+    use brotli::CompressorWriter;
+    use flate2::write::GzEncoder;
+    use futures::{Future, Stream};
+    use hyper::header::{HeaderValue, CONTENT_ENCODING};
+    use hyper::{Body, StatusCode};
+    use std::io::Write;
+
+    fn execute(req: Request) -> Result<(StatusCode, Vec<u8>), HttpError> {
+        let mut app = App::new();
+        app.add(decompression().max_size(64));
+        app.add(|req: Request, mut res: Response, _: (), _| {
+            req.into_body()
+                .concat2()
+                .map_err(|_| HttpError::Status(StatusCode::BAD_REQUEST))
+                .and_then(move |body| res.body(Body::from(body)).map_err(HttpError::Http))
+        });
+        app.build()
+            .execute(req, Response::new(), (), default_fallback)
+            .wait()
+            .map(|res| {
+                let status = res.status();
+                (status, res.into_body().concat2().wait().unwrap().to_vec())
+            })
+    }
+
+    fn gzip(data: &[u8]) -> Vec<u8> {
+        let mut encoder = GzEncoder::new(Vec::new(), Default::default());
+        encoder.write_all(data).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    #[test]
+    fn decodes_gzip() {
+        let req = hyper::Request::post("/")
+            .header(CONTENT_ENCODING, "gzip")
+            .body(gzip(b"Hello World!").into())
+            .unwrap();
+        assert_eq!(
+            execute(req).unwrap(),
+            (StatusCode::OK, b"Hello World!".to_vec())
+        );
+    }
+
+    #[test]
+    fn passes_unencoded_bodies() {
+        let req = hyper::Request::post("/").body("plain".into()).unwrap();
+        assert_eq!(execute(req).unwrap(), (StatusCode::OK, b"plain".to_vec()));
+    }
+
+    #[test]
+    fn rejects_unsupported_encoding() {
+        let req = hyper::Request::post("/")
+            .header(CONTENT_ENCODING, "compress")
+            .body(Body::empty())
+            .unwrap();
+        assert_eq!(execute(req).unwrap().0, StatusCode::UNSUPPORTED_MEDIA_TYPE);
+    }
+
+    #[test]
+    fn enforces_max_size() {
+        let req = hyper::Request::post("/")
+            .header(CONTENT_ENCODING, "gzip")
+            .body(gzip(&[b'a'; 1024]).into())
+            .unwrap();
+        match execute(req) {
+            Err(HttpError::Status(status)) => assert_eq!(status, StatusCode::PAYLOAD_TOO_LARGE),
+            _ => panic!("expected payload too large error"),
+        }
+    }
+
+    #[test]
+    fn stops_decoding_bombs() {
+        // 16 MiB of zeros compress to a few kilobytes
+        let zeros = vec![0; 16 * 1024 * 1024];
+        let mut brotli = CompressorWriter::new(Vec::new(), 4096, 5, 22);
+        brotli.write_all(&zeros).unwrap();
+        let bombs = vec![("gzip", gzip(&zeros)), ("br", brotli.into_inner())];
+
+        for (encoding, bomb) in bombs {
+            assert!(bomb.len() < 64 * 1024);
+
+            let mut decoder = Decoder::new(&HeaderValue::from_static(encoding), 1024 * 1024)
+                .unwrap()
+                .unwrap();
+            let err = decoder.write(&bomb).unwrap_err();
+            assert!(err.get_ref().unwrap().is::<TooLarge>());
+
+            let req = hyper::Request::post("/")
+                .header(CONTENT_ENCODING, encoding)
+                .body(bomb.into())
+                .unwrap();
+            match execute(req) {
+                Err(HttpError::Status(status)) => {
+                    assert_eq!(status, StatusCode::PAYLOAD_TOO_LARGE)
+                }
+                _ => panic!("expected payload too large error"),
+            }
+        }
+    }
+
+    #[test]
+    fn rejects_corrupt_bodies() {
+        let req = hyper::Request::post("/")
+            .header(CONTENT_ENCODING, "gzip")
+            .body("not gzip".into())
+            .unwrap();
+        match execute(req) {
+            Err(HttpError::Status(status)) => assert_eq!(status, StatusCode::BAD_REQUEST),
+            _ => panic!("expected bad request error"),
+        }
+    }
+}
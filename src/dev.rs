@@ -0,0 +1,312 @@
+//! The developer error page. Only compiled into debug builds, so that it can never show up in
+//! production, regardless of how the app is configured.
+
+use std::backtrace::{Backtrace, BacktraceStatus};
+use std::error::Error as StdError;
+use std::fmt::Write;
+use std::sync::{Arc, Mutex};
+
+use crate::{HttpError, HttpResponse, Panic, Request, ResponseFuture};
+use futures::{Future, Poll};
+use hyper::header::{HeaderValue, CONTENT_TYPE};
+use hyper::{Body, StatusCode};
+
+/// Records which middlewares and mounts a request passed through. Lives in the extensions of the
+/// request and of the request head kept by `Serve`.
+#[derive(Default)]
+pub(crate) struct Trace {
+    state: Mutex<TraceState>,
+}
+
+#[derive(Default)]
+struct TraceState {
+    /// Positions of the middlewares that are currently executing, innermost last.
+    stack: Vec<usize>,
+    /// The stack at the time the first error was returned.
+    failed: Option<Vec<usize>>,
+    mounts: Vec<String>,
+}
+
+impl Trace {
+    pub(crate) fn of(req: &Request) -> Option<Arc<Trace>> {
+        req.extensions().get::<Arc<Trace>>().cloned()
+    }
+
+    /// Calls the middleware at `pos` and tracks the future it returns.
+    pub(crate) fn middleware<F>(self: Arc<Self>, pos: usize, handle: F) -> ResponseFuture
+    where
+        F: FnOnce() -> ResponseFuture,
+    {
+        self.enter(pos);
+        let inner = handle();
+        self.leave();
+        Box::new(Traced {
+            pos,
+            trace: self,
+            inner,
+        })
+    }
+
+    pub(crate) fn mount(&self, prefix: &str) {
+        self.state.lock().unwrap().mounts.push(prefix.to_owned());
+    }
+
+    fn enter(&self, pos: usize) {
+        self.state.lock().unwrap().stack.push(pos);
+    }
+
+    fn leave(&self) {
+        self.state.lock().unwrap().stack.pop();
+    }
+
+    fn fail(&self) {
+        let mut state = self.state.lock().unwrap();
+        if state.failed.is_none() {
+            state.failed = Some(state.stack.clone());
+        }
+    }
+}
+
+struct Traced {
+    pos: usize,
+    trace: Arc<Trace>,
+    inner: ResponseFuture,
+}
+
+impl Future for Traced {
+    type Item = HttpResponse;
+    type Error = HttpError;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        // a panic skips `leave`, which keeps the panicking middleware on top of the stack
+        self.trace.enter(self.pos);
+        let result = self.inner.poll();
+        if result.is_err() {
+            self.trace.fail();
+        }
+        self.trace.leave();
+        result
+    }
+}
+
+/// Only errors that point to a bug are worth a developer error page, not e.g. a `404`.
+pub(crate) fn applies(err: &HttpError) -> bool {
+    match *err {
+        HttpError::Http(_) | HttpError::Internal(_) => true,
+        _ => err.status().is_server_error(),
+    }
+}
+
+pub(crate) fn render_error(err: &HttpError, head: &http::request::Parts) -> HttpResponse {
+    let mut chain = vec![err.to_string()];
+    let mut source = err.source();
+    while let Some(err) = source {
+        chain.push(err.to_string());
+        source = err.source();
+    }
+    let backtrace = match *err {
+        HttpError::Internal(ref internal) => Some(internal.backtrace()),
+        _ => None,
+    };
+    let stack = trace(head).and_then(|trace| trace.state.lock().unwrap().failed.clone());
+    render(err.status(), "Error", &chain, backtrace, stack, head)
+}
+
+pub(crate) fn render_panic(panic: &Panic, head: &http::request::Parts) -> HttpResponse {
+    let stack = trace(head).map(|trace| trace.state.lock().unwrap().stack.clone());
+    render(
+        StatusCode::INTERNAL_SERVER_ERROR,
+        "Panic",
+        &[panic.to_string()],
+        panic.backtrace.as_ref(),
+        stack,
+        head,
+    )
+}
+
+fn trace(head: &http::request::Parts) -> Option<&Arc<Trace>> {
+    head.extensions.get::<Arc<Trace>>()
+}
+
+fn render(
+    status: StatusCode,
+    kind: &str,
+    chain: &[String],
+    backtrace: Option<&Backtrace>,
+    stack: Option<Vec<usize>>,
+    head: &http::request::Parts,
+) -> HttpResponse {
+    let mut html = String::new();
+    let _ = write!(
+        html,
+        "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<title>{} - {} {}</title>\n\
+         <style>body {{ font-family: sans-serif; margin: 2em; }} \
+         pre {{ background: #f4f4f4; padding: 1em; overflow: auto; }} \
+         th {{ text-align: left; padding-right: 1em; }}</style>\n</head>\n<body>\n",
+        kind,
+        escape(head.method.as_str()),
+        escape(&head.uri.to_string()),
+    );
+
+    let _ = write!(html, "<h1>{}</h1>\n<ol>\n", kind);
+    for err in chain {
+        let _ = writeln!(html, "<li><pre>{}</pre></li>", escape(err));
+    }
+    html.push_str("</ol>\n");
+
+    html.push_str("<h2>Middleware</h2>\n");
+    match stack {
+        Some(ref stack) if !stack.is_empty() => {
+            let path = stack
+                .iter()
+                .map(|pos| format!("#{}", pos))
+                .collect::<Vec<_>>()
+                .join(" &rsaquo; ");
+            let _ = writeln!(
+                html,
+                "<p>Raised by middleware #{} (call stack: {})</p>",
+                stack[stack.len() - 1],
+                path
+            );
+        }
+        _ => html.push_str("<p>Unknown</p>\n"),
+    }
+
+    html.push_str("<h2>Backtrace</h2>\n");
+    match backtrace {
+        Some(backtrace) if backtrace.status() == BacktraceStatus::Captured => {
+            let _ = writeln!(html, "<pre>{}</pre>", escape(&backtrace.to_string()));
+        }
+        _ => html.push_str("<p>Not captured, set <code>RUST_BACKTRACE=1</code> to enable.</p>\n"),
+    }
+
+    let _ = write!(
+        html,
+        "<h2>Request</h2>\n<table>\n<tr><th>Method</th><td>{}</td></tr>\n\
+         <tr><th>URI</th><td>{}</td></tr>\n<tr><th>Version</th><td>{:?}</td></tr>\n",
+        escape(head.method.as_str()),
+        escape(&head.uri.to_string()),
+        head.version,
+    );
+    if let Some(trace) = trace(head) {
+        let mounts = trace.state.lock().unwrap().mounts.join(", ");
+        if !mounts.is_empty() {
+            let _ = writeln!(html, "<tr><th>Mounts</th><td>{}</td></tr>", escape(&mounts));
+        }
+    }
+    html.push_str("</table>\n<h3>Headers</h3>\n<table>\n");
+    for (name, value) in &head.headers {
+        let _ = writeln!(
+            html,
+            "<tr><th>{}</th><td>{}</td></tr>",
+            escape(name.as_str()),
+            escape(&String::from_utf8_lossy(value.as_bytes())),
+        );
+    }
+    html.push_str("</table>\n</body>\n</html>\n");
+
+    let mut res = HttpResponse::new(Body::from(html));
+    *res.status_mut() = status;
+    res.headers_mut().insert(
+        CONTENT_TYPE,
+        HeaderValue::from_static("text/html; charset=utf-8"),
+    );
+    res
+}
+
+fn escape(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{mount, App, Context, HttpError, Next, Request, Response};
+    use futures::{Future, Stream};
+    use hyper::header::CONTENT_TYPE;
+    use hyper::service::Service;
+    use hyper::{Body, StatusCode};
+    use std::io;
+
+    fn app() -> App<()> {
+        let mut app = App::new();
+        app.dev_error_page(true);
+        app.add(|req: Request, res, state, next: Next<()>| next(req, res, state));
+        app.add(mount(
+            "/api",
+            |req: Request, _res: Response, _state, _next| {
+                if req.uri().path() == "/panic" {
+                    panic!("<boom>");
+                }
+                Err::<Response, _>(io::Error::other("connection refused")).context("loading user")
+            },
+        ));
+        app.build()
+    }
+
+    fn call(path: &str) -> (StatusCode, Option<String>, String) {
+        let req = hyper::Request::get(path)
+            .header("x-test", "dev")
+            .body(Body::empty())
+            .unwrap();
+        let res = app().serve(|| ()).call(req).wait().unwrap();
+        let content_type = res
+            .headers()
+            .get(CONTENT_TYPE)
+            .map(|v| v.to_str().unwrap().to_owned());
+        let status = res.status();
+        let body = res.into_body().concat2().wait().unwrap();
+        (
+            status,
+            content_type,
+            String::from_utf8(body.to_vec()).unwrap(),
+        )
+    }
+
+    #[test]
+    fn renders_errors() {
+        let (status, content_type, body) = call("/api/user");
+        assert_eq!(status, StatusCode::INTERNAL_SERVER_ERROR);
+        assert_eq!(content_type.unwrap(), "text/html; charset=utf-8");
+        assert!(body.contains("Internal error: loading user"));
+        assert!(body.contains("connection refused"));
+        assert!(body.contains("Raised by middleware #1 (call stack: #0 &rsaquo; #1)"));
+        assert!(body.contains("<tr><th>Mounts</th><td>/api</td></tr>"));
+        assert!(body.contains("<tr><th>x-test</th><td>dev</td></tr>"));
+    }
+
+    #[test]
+    fn renders_panics() {
+        let (status, _, body) = call("/api/panic");
+        assert_eq!(status, StatusCode::INTERNAL_SERVER_ERROR);
+        assert!(body.contains("&lt;boom&gt;"));
+        assert!(body.contains("Raised by middleware #1 (call stack: #0 &rsaquo; #1)"));
+    }
+
+    #[test]
+    fn skips_client_errors() {
+        let (status, content_type, _) = call("/missing");
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert_eq!(content_type, None);
+    }
+
+    #[test]
+    fn disabled_by_default() {
+        let mut app = App::new();
+        app.add(|_req, _res, _state, _next| Err::<Response, _>(HttpError::internal("secret")));
+        let req = hyper::Request::get("/").body(Body::empty()).unwrap();
+        let res = app.build().serve(|| ()).call(req).wait().unwrap();
+        let body = res.into_body().concat2().wait().unwrap();
+        assert_eq!(&body[..], b"Internal Server Error");
+    }
+}
@@ -0,0 +1,390 @@
+//! Static assets that are embedded into the binary at build time.
+//!
+//! Embedding requires a build script, so the crate has to be added to the `[build-dependencies]`
+//! as well as the `[dependencies]`. The directory is prepared from the build script:
+//!
+//! ```ignore
+//! // build.rs
+//! fn main() {
+//!     web::embed::generate("assets", "./assets").unwrap();
+//! }
+//! ```
+//!
+//! and afterwards included and served with:
+//!
+//! ```ignore
+//! static ASSETS: web::EmbeddedDir = web::embed_dir!("assets");
+//!
+//! app.add(web::embedded(&ASSETS).dev(cfg!(debug_assertions)));
+//! ```
+
+use std::borrow::Cow;
+use std::env;
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+use crate::encoding::{self, Encoding};
+use crate::etag::etag;
+use crate::static_files::{respond, sanitize};
+use crate::{IntoResponse, Middleware, Next, Request, Response, ResponseFuture};
+use hyper::header::{HeaderValue, VARY};
+use hyper::{Body, Method};
+
+pub struct EmbeddedFile {
+    /// The path relative to the embedded directory, using `/` as separator.
+    pub path: &'static str,
+    pub data: &'static [u8],
+    pub etag: &'static str,
+}
+
+pub struct EmbeddedDir {
+    /// The directory the files were read from at build time.
+    pub root: &'static str,
+    /// The embedded files, sorted by their path.
+    pub files: &'static [EmbeddedFile],
+}
+
+/// Includes a directory that has been prepared with [`generate`] under the same `name`.
+#[macro_export]
+macro_rules! embed_dir {
+    ($name:expr) => {{
+        // the generated code refers to these, which works however the crate is named
+        #[allow(unused_imports)]
+        use $crate::embed::{EmbeddedDir, EmbeddedFile};
+        include!(concat!(env!("OUT_DIR"), "/web_embed_", $name, ".rs"))
+    }};
+}
+
+/// Prepares the directory `dir` to be embedded with `embed_dir!(name)`. Must be called from a
+/// build script.
+pub fn generate<P: AsRef<Path>>(name: &str, dir: P) -> io::Result<()> {
+    let out_dir = env::var_os("OUT_DIR").ok_or_else(|| {
+        io::Error::other("OUT_DIR is not set, generate must be called from a build script")
+    })?;
+    let root = fs::canonicalize(dir)?;
+    let mut files = Vec::new();
+    collect(&root, &mut Vec::new(), &mut files)?;
+    files.sort();
+
+    let out = PathBuf::from(out_dir).join(format!("web_embed_{}.rs", name));
+    let mut out = io::BufWriter::new(fs::File::create(out)?);
+    write_dir(&mut out, &root, &files)?;
+    out.flush()?;
+
+    println!("cargo:rerun-if-changed={}", root.display());
+    Ok(())
+}
+
+/// Writes the expression `embed_dir!` includes, which uses the names it imports.
+fn write_dir<W: Write>(out: &mut W, root: &Path, files: &[(String, PathBuf)]) -> io::Result<()> {
+    writeln!(
+        out,
+        "EmbeddedDir {{ root: {:?}, files: &[",
+        root.to_string_lossy()
+    )?;
+    for (path, abs) in files {
+        let data = fs::read(abs)?;
+        writeln!(
+            out,
+            "EmbeddedFile {{ path: {:?}, data: include_bytes!({:?}), etag: {:?} }},",
+            path,
+            abs.to_string_lossy(),
+            etag(&data)
+        )?;
+    }
+    writeln!(out, "] }}")
+}
+
+fn collect(
+    dir: &Path,
+    prefix: &mut Vec<String>,
+    files: &mut Vec<(String, PathBuf)>,
+) -> io::Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().into_owned();
+        prefix.push(name);
+        if entry.file_type()?.is_dir() {
+            collect(&entry.path(), prefix, files)?;
+        } else {
+            files.push((prefix.join("/"), entry.path()));
+        }
+        prefix.pop();
+    }
+    Ok(())
+}
+
+pub struct Embedded {
+    dir: &'static EmbeddedDir,
+    precompressed: bool,
+    dev: bool,
+}
+
+/// Serves the files of an embedded directory. Requests that do not match a file are passed on to
+/// the next middleware.
+pub fn embedded(dir: &'static EmbeddedDir) -> Embedded {
+    Embedded {
+        dir,
+        precompressed: true,
+        dev: false,
+    }
+}
+
+struct Asset {
+    data: Cow<'static, [u8]>,
+    etag: Cow<'static, str>,
+}
+
+impl Embedded {
+    /// Whether to look for precompressed `.br` and `.gz` siblings of the requested file
+    /// (enabled by default).
+    pub fn precompressed(mut self, enabled: bool) -> Self {
+        self.precompressed = enabled;
+        self
+    }
+
+    /// Read the files from the directory they have been embedded from instead, so that they can
+    /// be changed without a rebuild.
+    pub fn dev(mut self, enabled: bool) -> Self {
+        self.dev = enabled;
+        self
+    }
+
+    fn get(&self, path: &str) -> Option<Asset> {
+        if self.dev {
+            let path = Path::new(self.dir.root).join(path);
+            if !path.is_file() {
+                return None;
+            }
+            let data = fs::read(path).ok()?;
+            Some(Asset {
+                etag: Cow::Owned(etag(&data)),
+                data: Cow::Owned(data),
+            })
+        } else {
+            let files = self.dir.files;
+            let ix = files.binary_search_by_key(&path, |f| f.path).ok()?;
+            Some(Asset {
+                data: Cow::Borrowed(files[ix].data),
+                etag: Cow::Borrowed(files[ix].etag),
+            })
+        }
+    }
+
+    fn lookup(&self, path: &str) -> Option<(String, Asset)> {
+        let rel = sanitize(path)?;
+        let key = rel
+            .components()
+            .map(|c| c.as_os_str().to_str())
+            .collect::<Option<Vec<_>>>()?
+            .join("/");
+
+        if !key.is_empty() {
+            if let Some(asset) = self.get(&key) {
+                return Some((key, asset));
+            }
+        }
+        let index = if key.is_empty() {
+            "index.html".to_string()
+        } else {
+            key + "/index.html"
+        };
+        self.get(&index).map(|asset| (index, asset))
+    }
+}
+
+impl<S> Middleware<S> for Embedded
+where
+    S: 'static,
+{
+    fn handle(&self, req: Request, mut res: Response, state: S, next: Next<S>) -> ResponseFuture {
+        if req.method() != Method::GET && req.method() != Method::HEAD {
+            return next(req, res, state);
+        }
+
+        let (path, mut asset) = match self.lookup(req.uri().path()) {
+            Some(found) => found,
+            None => return next(req, res, state),
+        };
+
+        let mut encoding = Encoding::Identity;
+        if self.precompressed {
+            let mut variants = [Encoding::Brotli, Encoding::Gzip]
+                .iter()
+                .filter_map(|enc| {
+                    let ext = enc.extension()?;
+                    self.get(&format!("{}.{}", path, ext))
+                        .map(|variant| (*enc, variant))
+                })
+                .collect::<Vec<_>>();
+            if !variants.is_empty() {
+                res.header(VARY, HeaderValue::from_static("Accept-Encoding"));
+                let mut available = variants.iter().map(|(enc, _)| *enc).collect::<Vec<_>>();
+                available.push(Encoding::Identity);
+                if let Some(enc) = encoding::negotiate(req.headers(), &available) {
+                    if let Some(ix) = variants.iter().position(|(e, _)| *e == enc) {
+                        asset = variants.swap_remove(ix).1;
+                        encoding = enc;
+                    }
+                }
+            }
+        }
+
+        let mime = mime_guess::from_path(&path).first_or_octet_stream();
+        let data = asset.data;
+        respond(
+            &req,
+            res,
+            &mime,
+            encoding,
+            Some(&asset.etag),
+            data.len() as u64,
+            |offset, len| {
+                let range = offset as usize..(offset + len) as usize;
+                Ok(match data {
+                    Cow::Borrowed(data) => Body::from(&data[range]),
+                    Cow::Owned(data) => Body::from(data[range].to_vec()),
+                })
+            },
+        )
+        .into_response()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{embedded, write_dir, EmbeddedDir, EmbeddedFile};
+    use crate::etag::etag;
+    use crate::{default_fallback, App, Request};
+    use futures::{Future, Stream};
+    use hyper::header::{ACCEPT_ENCODING, CONTENT_ENCODING, CONTENT_TYPE, ETAG, IF_NONE_MATCH};
+    use hyper::{Body, StatusCode};
+    use std::fs;
+
+    static DIR: EmbeddedDir = EmbeddedDir {
+        root: "/nonexistent",
+        files: &[
+            EmbeddedFile {
+                path: "css/app.css",
+                data: b"body {}",
+                etag: "\"1\"",
+            },
+            EmbeddedFile {
+                path: "css/app.css.br",
+                data: b"brotli",
+                etag: "\"2\"",
+            },
+            EmbeddedFile {
+                path: "index.html",
+                data: b"<html></html>",
+                etag: "\"3\"",
+            },
+        ],
+    };
+
+    fn get(app: App<()>, req: Request) -> hyper::Response<Vec<u8>> {
+        let res = app
+            .execute(req, crate::Response::new(), (), default_fallback)
+            .wait()
+            .unwrap();
+        let (parts, body) = res.into_parts();
+        let body = body.concat2().wait().unwrap().to_vec();
+        hyper::Response::from_parts(parts, body)
+    }
+
+    fn app(dev: bool) -> App<()> {
+        let mut app = App::new();
+        app.add(embedded(&DIR).dev(dev));
+        app.build()
+    }
+
+    #[test]
+    fn serves_embedded_file() {
+        let req = hyper::Request::get("/css/app.css")
+            .body(Body::empty())
+            .unwrap();
+        let res = get(app(false), req);
+
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(res.headers()[CONTENT_TYPE], "text/css");
+        assert_eq!(res.headers()[ETAG], "\"1\"");
+        assert_eq!(res.body(), b"body {}");
+    }
+
+    #[test]
+    fn serves_index() {
+        let req = hyper::Request::get("/").body(Body::empty()).unwrap();
+        let res = get(app(false), req);
+
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(res.body(), b"<html></html>");
+    }
+
+    #[test]
+    fn serves_precompressed_variant() {
+        let req = hyper::Request::get("/css/app.css")
+            .header(ACCEPT_ENCODING, "br")
+            .body(Body::empty())
+            .unwrap();
+        let res = get(app(false), req);
+
+        assert_eq!(res.headers()[CONTENT_ENCODING], "br");
+        assert_eq!(res.headers()[CONTENT_TYPE], "text/css");
+        assert_eq!(res.headers()[ETAG], "\"2\"");
+        assert_eq!(res.body(), b"brotli");
+    }
+
+    #[test]
+    fn not_modified() {
+        let req = hyper::Request::get("/css/app.css")
+            .header(IF_NONE_MATCH, "\"1\"")
+            .body(Body::empty())
+            .unwrap();
+        let res = get(app(false), req);
+
+        assert_eq!(res.status(), StatusCode::NOT_MODIFIED);
+        assert!(res.body().is_empty());
+    }
+
+    #[test]
+    fn generates_code_independent_of_the_crate_name() {
+        let root = std::env::temp_dir().join("web-embed-generate");
+        fs::create_dir_all(&root).unwrap();
+        fs::write(root.join("app.js"), "app").unwrap();
+
+        let mut out = Vec::new();
+        write_dir(
+            &mut out,
+            &root,
+            &[("app.js".to_owned(), root.join("app.js"))],
+        )
+        .unwrap();
+        let out = String::from_utf8(out).unwrap();
+
+        assert!(out.starts_with("EmbeddedDir {"));
+        assert!(out.contains("EmbeddedFile { path: \"app.js\", data: include_bytes!("));
+        assert!(out.contains(&format!("etag: {:?}", etag(b"app"))));
+        assert!(!out.contains("::web"));
+    }
+
+    #[test]
+    fn dev_reads_from_disk() {
+        let root = std::env::temp_dir().join("web-embed-dev");
+        fs::create_dir_all(&root).unwrap();
+        fs::write(root.join("app.js"), "changed").unwrap();
+        let dir = Box::leak(Box::new(EmbeddedDir {
+            root: Box::leak(root.to_string_lossy().into_owned().into_boxed_str()),
+            files: &[],
+        }));
+
+        let mut app = App::new();
+        app.add(embedded(dir).dev(true));
+        let req = hyper::Request::get("/app.js").body(Body::empty()).unwrap();
+        let res = get(app.build(), req);
+
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(res.headers()[ETAG], etag(b"changed").as_str());
+        assert_eq!(res.body(), b"changed");
+    }
+}
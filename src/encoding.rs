@@ -0,0 +1,146 @@
+use hyper::header::{HeaderMap, ACCEPT_ENCODING};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Encoding {
+    Brotli,
+    Gzip,
+    Deflate,
+    Identity,
+}
+
+impl Encoding {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Encoding::Brotli => "br",
+            Encoding::Gzip => "gzip",
+            Encoding::Deflate => "deflate",
+            Encoding::Identity => "identity",
+        }
+    }
+
+    /// The file extension of precompressed siblings, e.g. `app.js.br`.
+    pub fn extension(self) -> Option<&'static str> {
+        match self {
+            Encoding::Brotli => Some("br"),
+            Encoding::Gzip => Some("gz"),
+            Encoding::Deflate | Encoding::Identity => None,
+        }
+    }
+}
+
+/// Picks the encoding out of `available` with the highest q-value in the `Accept-Encoding`
+/// header. Ties are resolved by the order of `available`. Returns `None` if none of them is
+/// acceptable to the client.
+pub fn negotiate(headers: &HeaderMap, available: &[Encoding]) -> Option<Encoding> {
+    let accept = match headers.get(ACCEPT_ENCODING).and_then(|v| v.to_str().ok()) {
+        Some(accept) => accept,
+        // no header means that only identity is expected
+        None => {
+            return available
+                .iter()
+                .cloned()
+                .find(|enc| *enc == Encoding::Identity)
+        }
+    };
+
+    let mut best: Option<(Encoding, u16)> = None;
+    for enc in available {
+        let q = quality(accept, *enc);
+        if q > 0 && best.map(|(_, best_q)| q > best_q).unwrap_or(true) {
+            best = Some((*enc, q));
+        }
+    }
+    best.map(|(enc, _)| enc)
+}
+
+/// Returns the q-value (scaled to 0..=1000) the client assigned to `enc`.
+fn quality(accept: &str, enc: Encoding) -> u16 {
+    let mut wildcard = None;
+    for item in accept.split(',') {
+        let mut parts = item.split(';');
+        let name = parts.next().unwrap_or("").trim();
+        let q = parts
+            .filter_map(|param| {
+                let param = param.trim();
+                if param.starts_with("q=") || param.starts_with("Q=") {
+                    parse_q(&param[2..])
+                } else {
+                    None
+                }
+            })
+            .next()
+            .unwrap_or(1000);
+
+        if name.eq_ignore_ascii_case(enc.as_str())
+            || (enc == Encoding::Gzip && name.eq_ignore_ascii_case("x-gzip"))
+        {
+            return q;
+        }
+        if name == "*" {
+            wildcard = Some(q);
+        }
+    }
+
+    match (wildcard, enc) {
+        (Some(q), _) => q,
+        // identity is always acceptable unless explicitly excluded
+        (None, Encoding::Identity) => 1,
+        (None, _) => 0,
+    }
+}
+
+fn parse_q(s: &str) -> Option<u16> {
+    let q: f32 = s.trim().parse().ok()?;
+    if !(0.0..=1.0).contains(&q) {
+        return None;
+    }
+    Some((q * 1000.0).round() as u16)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{negotiate, Encoding};
+    use hyper::header::{HeaderMap, HeaderValue, ACCEPT_ENCODING};
+
+    fn headers(accept: &'static str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(ACCEPT_ENCODING, HeaderValue::from_static(accept));
+        headers
+    }
+
+    const ALL: &[Encoding] = &[Encoding::Brotli, Encoding::Gzip, Encoding::Identity];
+
+    #[test]
+    fn no_header() {
+        assert_eq!(negotiate(&HeaderMap::new(), ALL), Some(Encoding::Identity));
+    }
+
+    #[test]
+    fn prefers_order_on_ties() {
+        assert_eq!(
+            negotiate(&headers("gzip, deflate, br"), ALL),
+            Some(Encoding::Brotli)
+        );
+    }
+
+    #[test]
+    fn respects_q_values() {
+        assert_eq!(
+            negotiate(&headers("br;q=0.5, gzip;q=0.8"), ALL),
+            Some(Encoding::Gzip)
+        );
+        assert_eq!(
+            negotiate(&headers("br;q=0, gzip;q=0"), ALL),
+            Some(Encoding::Identity)
+        );
+    }
+
+    #[test]
+    fn identity_excluded() {
+        assert_eq!(
+            negotiate(&headers("gzip, identity;q=0"), &[Encoding::Identity]),
+            None
+        );
+        assert_eq!(negotiate(&headers("*;q=0"), &[Encoding::Identity]), None);
+    }
+}
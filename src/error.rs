@@ -1,15 +1,32 @@
+use std::backtrace::Backtrace;
 use std::error::Error as StdError;
 use std::fmt;
+use std::io;
+use std::mem;
+use std::num::ParseIntError;
+use std::str::Utf8Error;
 
 use http;
+use hyper::header::InvalidHeaderValue;
+#[cfg(not(feature = "json"))]
+use hyper::header::{HeaderValue, CONTENT_TYPE};
 pub use hyper::StatusCode;
 use hyper::{Body, Response};
 
+#[cfg(feature = "json")]
+pub use crate::problem::Problem;
+
+/// Variants depend on the enabled features, e.g. `Problem` requires `json`, so matches have to
+/// include a wildcard arm. Large payloads are boxed to keep results with this error small.
 #[derive(Debug)]
+#[non_exhaustive]
 pub enum HttpError {
     Status(StatusCode),
     Response(Response<Body>),
     Http(http::Error),
+    #[cfg(feature = "json")]
+    Problem(Box<Problem>),
+    Internal(Box<Internal>),
 }
 
 impl HttpError {
@@ -26,8 +43,43 @@ impl HttpError {
             }
             HttpError::Response(resp) => Ok(resp),
             HttpError::Http(err) => Err(err),
+            #[cfg(feature = "json")]
+            HttpError::Problem(problem) => (*problem).into_response(),
+            HttpError::Internal(internal) => {
+                let status = internal.status;
+                let mut res = Response::builder();
+                res.status(status);
+                match internal
+                    .message
+                    .or_else(|| status.canonical_reason().map(str::to_owned))
+                {
+                    Some(message) => res.body(message.into()),
+                    None => res.body(Body::empty()),
+                }
+            }
         }
     }
+
+    /// The status of the response the error results in.
+    pub fn status(&self) -> StatusCode {
+        match *self {
+            HttpError::Status(status) => status,
+            HttpError::Response(ref res) => res.status(),
+            HttpError::Http(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            #[cfg(feature = "json")]
+            HttpError::Problem(ref problem) => problem.status(),
+            HttpError::Internal(ref internal) => internal.status(),
+        }
+    }
+
+    /// Wraps `err` into a `500 Internal Server Error` whose response does not reveal anything
+    /// about `err`.
+    pub fn internal<E>(err: E) -> Self
+    where
+        E: Into<Box<dyn StdError + Send + Sync>>,
+    {
+        HttpError::Internal(Box::new(Internal::new(err)))
+    }
 }
 
 impl fmt::Display for HttpError {
@@ -36,6 +88,9 @@ impl fmt::Display for HttpError {
             HttpError::Status(ref status) => write!(f, "Error with status: {}", status),
             HttpError::Response(ref resp) => write!(f, "Error with response:\n{:?}", resp),
             HttpError::Http(ref err) => write!(f, "Error with http:\n{:?}", err),
+            #[cfg(feature = "json")]
+            HttpError::Problem(ref problem) => write!(f, "Error with problem: {}", problem),
+            HttpError::Internal(ref internal) => write!(f, "Internal error: {}", internal),
         }
     }
 }
@@ -46,10 +101,148 @@ impl StdError for HttpError {
             HttpError::Status(_) => "Error with status code",
             HttpError::Response(_) => "Error with response",
             HttpError::Http(_) => "Error with http",
+            #[cfg(feature = "json")]
+            HttpError::Problem(_) => "Error with problem",
+            HttpError::Internal(_) => "Internal error",
+        }
+    }
+
+    fn source(&self) -> Option<&(dyn StdError + 'static)> {
+        match *self {
+            HttpError::Http(ref err) => Some(err),
+            HttpError::Internal(ref internal) => internal.source(),
+            _ => None,
         }
     }
 }
 
+/// An error caused by something the client is not supposed to know about, e.g. a failing
+/// database query. Only its status and public message end up in the response, the underlying
+/// error is available through `source()` for logging.
+#[derive(Debug)]
+pub struct Internal {
+    status: StatusCode,
+    message: Option<String>,
+    context: Option<String>,
+    source: Box<dyn StdError + Send + Sync>,
+    backtrace: Backtrace,
+}
+
+impl Internal {
+    /// Wraps `err` into a `500 Internal Server Error`.
+    pub fn new<E>(err: E) -> Self
+    where
+        E: Into<Box<dyn StdError + Send + Sync>>,
+    {
+        Internal {
+            status: StatusCode::INTERNAL_SERVER_ERROR,
+            message: None,
+            context: None,
+            source: err.into(),
+            backtrace: Backtrace::capture(),
+        }
+    }
+
+    pub fn with_status<E>(status: StatusCode, err: E) -> Self
+    where
+        E: Into<Box<dyn StdError + Send + Sync>>,
+    {
+        Internal {
+            status,
+            ..Internal::new(err)
+        }
+    }
+
+    /// The message sent to the client instead of the canonical reason of the status.
+    pub fn message<M: Into<String>>(mut self, message: M) -> Self {
+        self.message = Some(message.into());
+        self
+    }
+
+    /// Describes what was being done when the error occurred.
+    pub fn context<C: fmt::Display>(mut self, context: C) -> Self {
+        if self.context.is_some() {
+            let message = self.message.take();
+            let backtrace = mem::replace(&mut self.backtrace, Backtrace::disabled());
+            self = Internal {
+                status: self.status,
+                message,
+                context: None,
+                source: Box::new(self),
+                backtrace,
+            };
+        }
+        self.context = Some(context.to_string());
+        self
+    }
+
+    pub fn status(&self) -> StatusCode {
+        self.status
+    }
+
+    pub fn public_message(&self) -> Option<&str> {
+        self.message.as_deref()
+    }
+
+    /// Where the error has been wrapped. Only captured if enabled with the `RUST_BACKTRACE` or
+    /// `RUST_LIB_BACKTRACE` environment variables.
+    pub fn backtrace(&self) -> &Backtrace {
+        &self.backtrace
+    }
+}
+
+impl fmt::Display for Internal {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.context {
+            Some(ref context) => write!(f, "{}", context),
+            None => write!(f, "{}", self.source),
+        }
+    }
+}
+
+impl StdError for Internal {
+    fn source(&self) -> Option<&(dyn StdError + 'static)> {
+        match self.context {
+            Some(_) => Some(&*self.source),
+            None => self.source.source(),
+        }
+    }
+}
+
+impl From<Internal> for HttpError {
+    fn from(internal: Internal) -> Self {
+        HttpError::Internal(Box::new(internal))
+    }
+}
+
+/// Adds context to errors, turning them into internal errors, e.g.
+/// `load_user(id).context("loading user")?`. The second parameter only tells the implementations
+/// for `HttpError` and other errors apart and is always inferred.
+pub trait Context<T, M> {
+    fn context<C: fmt::Display>(self, context: C) -> Result<T, HttpError>;
+}
+
+impl<T, E> Context<T, E> for Result<T, E>
+where
+    E: StdError + Send + Sync + 'static,
+{
+    fn context<C: fmt::Display>(self, context: C) -> Result<T, HttpError> {
+        self.map_err(|err| HttpError::Internal(Box::new(Internal::new(err).context(context))))
+    }
+}
+
+/// Only internal errors get the context, all others are meant for the client anyway.
+impl<T> Context<T, ()> for Result<T, HttpError> {
+    fn context<C: fmt::Display>(self, context: C) -> Result<T, HttpError> {
+        self.map_err(|err| match err {
+            HttpError::Internal(internal) => {
+                HttpError::Internal(Box::new((*internal).context(context)))
+            }
+            err => err,
+        })
+    }
+}
+
 impl From<StatusCode> for HttpError {
     fn from(status: StatusCode) -> Self {
         HttpError::Status(status)
@@ -62,6 +255,83 @@ impl From<http::Error> for HttpError {
     }
 }
 
+impl From<io::Error> for HttpError {
+    fn from(err: io::Error) -> Self {
+        let status = match err.kind() {
+            io::ErrorKind::NotFound => StatusCode::NOT_FOUND,
+            io::ErrorKind::PermissionDenied => StatusCode::FORBIDDEN,
+            _ => StatusCode::INTERNAL_SERVER_ERROR,
+        };
+        HttpError::Internal(Box::new(Internal::with_status(status, err)))
+    }
+}
+
+/// Most likely caused by parsing a part of the request, e.g. a path segment or query parameter.
+impl From<ParseIntError> for HttpError {
+    fn from(err: ParseIntError) -> Self {
+        HttpError::Internal(Box::new(Internal::with_status(
+            StatusCode::BAD_REQUEST,
+            err,
+        )))
+    }
+}
+
+/// Most likely caused by a request body that is not valid UTF-8.
+impl From<Utf8Error> for HttpError {
+    fn from(err: Utf8Error) -> Self {
+        HttpError::Internal(Box::new(Internal::with_status(
+            StatusCode::BAD_REQUEST,
+            err,
+        )))
+    }
+}
+
+impl From<hyper::Error> for HttpError {
+    fn from(err: hyper::Error) -> Self {
+        HttpError::internal(err)
+    }
+}
+
+impl From<InvalidHeaderValue> for HttpError {
+    fn from(err: InvalidHeaderValue) -> Self {
+        HttpError::internal(err)
+    }
+}
+
+/// The error the `ok!` and `ok_some!` macros return when given a message. With the `json`
+/// feature, it is a `Problem` with the message as its detail, otherwise a plain text response.
+#[doc(hidden)]
+pub fn message(status: StatusCode, msg: String) -> HttpError {
+    #[cfg(feature = "json")]
+    {
+        HttpError::Problem(Box::new(Problem::new(status).detail(msg)))
+    }
+    #[cfg(not(feature = "json"))]
+    {
+        Response::builder()
+            .status(status)
+            .header(CONTENT_TYPE, HeaderValue::from_static("text/plain"))
+            .body(msg.into())
+            .map(HttpError::Response)
+            .unwrap_or_else(HttpError::Http)
+    }
+}
+
+/// The error the macros return when given `json: body`, a response with `body` serialized as
+/// JSON.
+#[cfg(feature = "json")]
+#[doc(hidden)]
+pub fn json<T: serde::Serialize>(status: StatusCode, body: T) -> HttpError {
+    let mut res = Response::builder();
+    res.status(status);
+    match crate::json_response(res, body) {
+        Ok(res) => HttpError::Response(res),
+        Err(err) => err,
+    }
+}
+
+/// Returns early with `400 Bad Request` (or the given status) if the condition is false. A
+/// formatted message, or with the `json` feature `json: body`, can be added as the response body.
 #[macro_export]
 macro_rules! ok {
     ($cond:expr) => (
@@ -76,17 +346,20 @@ macro_rules! ok {
             return Err($crate::HttpError::Status($status));
         }
     );
+    ($cond:expr, $status:expr, json: $body:expr) => (
+        if !$cond {
+            return Err($crate::error::json($status, $body));
+        }
+    );
     ($cond:expr, $status:expr, $($arg:tt)+) => (
         if !$cond {
-            return Err(
-                $crate::HttpError::Response($crate::Response::new()
-                    .with_status($status)
-                    .with_body(format!($($arg)+)))
-            );
+            return Err($crate::error::message($status, format!($($arg)+)));
         }
     );
 }
 
+/// Unwraps an `Option`, returning early with `404 Not Found` (or the given status) on `None`.
+/// Accepts the same message arguments as `ok!`.
 #[macro_export]
 macro_rules! ok_some {
     ($option:expr) => (
@@ -105,14 +378,255 @@ macro_rules! ok_some {
             )
         }
     );
+    ($option:expr, $status:expr, json: $body:expr) => (
+        match $option {
+            Some(val) => val,
+            None => return Err($crate::error::json($status, $body))
+        }
+    );
     ($option:expr, $status:expr, $($arg:tt)+) => (
         match $option {
             Some(val) => val,
-            None => return Err(
-                $crate::HttpError::Response($crate::Response::new()
-                    .with_status($status)
-                    .with_body(format!($($arg)+)))
+            None => return Err($crate::error::message($status, format!($($arg)+)))
+        }
+    );
+}
+
+/// Unwraps a `Result`, returning early with `500 Internal Server Error` (or the given status) on
+/// `Err`. Accepts the same message arguments as `ok!`. The error can also be mapped to a status
+/// or `HttpError` explicitly, e.g. `ok_result!(res, err => status_for(&err))`.
+#[macro_export]
+macro_rules! ok_result {
+    ($result:expr) => (
+        match $result {
+            Ok(val) => val,
+            Err(_) => return Err(
+                $crate::HttpError::Status($crate::error::StatusCode::INTERNAL_SERVER_ERROR)
             )
         }
     );
+    ($result:expr, $err:ident => $map:expr) => (
+        match $result {
+            Ok(val) => val,
+            Err($err) => return Err($crate::HttpError::from($map))
+        }
+    );
+    ($result:expr, $status:expr) => (
+        match $result {
+            Ok(val) => val,
+            Err(_) => return Err($crate::HttpError::Status($status))
+        }
+    );
+    ($result:expr, $status:expr, json: $body:expr) => (
+        match $result {
+            Ok(val) => val,
+            Err(_) => return Err($crate::error::json($status, $body))
+        }
+    );
+    ($result:expr, $status:expr, $($arg:tt)+) => (
+        match $result {
+            Ok(val) => val,
+            Err(_) => return Err($crate::error::message($status, format!($($arg)+)))
+        }
+    );
+}
+
+/// Returns early with the given status. Accepts the same message arguments as `ok!`.
+#[macro_export]
+macro_rules! bail {
+    ($status:expr) => (
+        return Err($crate::HttpError::Status($status))
+    );
+    ($status:expr, json: $body:expr) => (
+        return Err($crate::error::json($status, $body))
+    );
+    ($status:expr, $($arg:tt)+) => (
+        return Err($crate::error::message($status, format!($($arg)+)))
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Context, HttpError, Internal, StatusCode};
+    use futures::{Future, Stream};
+    use hyper::header::CONTENT_TYPE;
+    use std::error::Error as StdError;
+    use std::io;
+
+    fn render(err: HttpError) -> (StatusCode, String, String) {
+        let res = err.into_response().unwrap();
+        let status = res.status();
+        let content_type = res
+            .headers()
+            .get(CONTENT_TYPE)
+            .map(|v| v.to_str().unwrap().to_owned())
+            .unwrap_or_default();
+        let body = res.into_body().concat2().wait().unwrap();
+        (
+            status,
+            content_type,
+            String::from_utf8(body.to_vec()).unwrap(),
+        )
+    }
+
+    #[test]
+    fn ok_result() {
+        fn parse(s: &str) -> Result<u32, HttpError> {
+            Ok(crate::ok_result!(s.parse::<u32>(), StatusCode::BAD_REQUEST))
+        }
+        fn parse_mapped(s: &str) -> Result<u32, HttpError> {
+            Ok(crate::ok_result!(s.parse::<u32>(), err => {
+                assert!(!err.to_string().is_empty());
+                StatusCode::UNPROCESSABLE_ENTITY
+            }))
+        }
+
+        assert_eq!(parse("42").unwrap(), 42);
+        match parse("x") {
+            Err(HttpError::Status(status)) => assert_eq!(status, StatusCode::BAD_REQUEST),
+            _ => panic!("expected bad request"),
+        }
+        match parse_mapped("x") {
+            Err(HttpError::Status(status)) => {
+                assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY)
+            }
+            _ => panic!("expected unprocessable entity"),
+        }
+    }
+
+    #[test]
+    fn bail() {
+        fn check(id: u32) -> Result<(), HttpError> {
+            if id == 0 {
+                crate::bail!(StatusCode::NOT_FOUND);
+            }
+            crate::bail!(StatusCode::FORBIDDEN, "no access to {}", id)
+        }
+
+        match check(0) {
+            Err(HttpError::Status(status)) => assert_eq!(status, StatusCode::NOT_FOUND),
+            _ => panic!("expected not found"),
+        }
+        assert_eq!(render(check(1).unwrap_err()).0, StatusCode::FORBIDDEN);
+    }
+
+    #[cfg(feature = "json")]
+    #[test]
+    fn json_bodies() {
+        fn check(name: Option<&str>) -> Result<&str, HttpError> {
+            let name = crate::ok_some!(
+                name,
+                StatusCode::UNPROCESSABLE_ENTITY,
+                json: serde_json::json!({ "field": "name" })
+            );
+            crate::ok!(!name.is_empty(), StatusCode::BAD_REQUEST, json: vec!["empty"]);
+            Ok(name)
+        }
+
+        assert_eq!(
+            render(check(None).unwrap_err()),
+            (
+                StatusCode::UNPROCESSABLE_ENTITY,
+                "application/json".to_owned(),
+                r#"{"field":"name"}"#.to_owned()
+            )
+        );
+        assert_eq!(render(check(Some("")).unwrap_err()).2, r#"["empty"]"#);
+    }
+
+    #[cfg(not(feature = "json"))]
+    #[test]
+    fn plain_text_messages() {
+        fn check(id: u32) -> Result<(), HttpError> {
+            crate::ok!(id > 0, StatusCode::BAD_REQUEST, "invalid id {}", id);
+            Ok(())
+        }
+
+        assert_eq!(
+            render(check(0).unwrap_err()),
+            (
+                StatusCode::BAD_REQUEST,
+                "text/plain".to_owned(),
+                "invalid id 0".to_owned()
+            )
+        );
+    }
+
+    #[test]
+    fn internal_error_context() {
+        fn load_user() -> Result<(), HttpError> {
+            Err(io::Error::other("connection refused")).context("loading user")
+        }
+        fn handler() -> Result<(), HttpError> {
+            load_user().context("rendering profile")
+        }
+
+        let err = handler().unwrap_err();
+        assert_eq!(err.to_string(), "Internal error: rendering profile");
+        let mut chain = Vec::new();
+        let mut source = err.source();
+        while let Some(err) = source {
+            chain.push(err.to_string());
+            source = err.source();
+        }
+        assert_eq!(chain, vec!["loading user", "connection refused"]);
+
+        assert_eq!(
+            render(err),
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                String::new(),
+                "Internal Server Error".to_owned()
+            )
+        );
+    }
+
+    #[test]
+    fn internal_error_public_message() {
+        let err: HttpError = Internal::with_status(StatusCode::BAD_GATEWAY, "upstream timed out")
+            .message("Try again later")
+            .into();
+        assert_eq!(render(err).2, "Try again later");
+
+        let res: Result<(), HttpError> = Err(StatusCode::NOT_FOUND.into());
+        match res.context("loading user") {
+            Err(HttpError::Status(status)) => assert_eq!(status, StatusCode::NOT_FOUND),
+            _ => panic!("expected not found"),
+        }
+    }
+
+    #[test]
+    fn conversions() {
+        fn status<E: Into<HttpError>>(err: E) -> StatusCode {
+            match err.into() {
+                HttpError::Internal(internal) => internal.status(),
+                _ => panic!("expected internal error"),
+            }
+        }
+
+        assert_eq!(
+            status(io::Error::from(io::ErrorKind::NotFound)),
+            StatusCode::NOT_FOUND
+        );
+        assert_eq!(
+            status(io::Error::from(io::ErrorKind::PermissionDenied)),
+            StatusCode::FORBIDDEN
+        );
+        assert_eq!(
+            status(io::Error::from(io::ErrorKind::BrokenPipe)),
+            StatusCode::INTERNAL_SERVER_ERROR
+        );
+        assert_eq!(
+            status("x".parse::<u8>().unwrap_err()),
+            StatusCode::BAD_REQUEST
+        );
+        assert_eq!(
+            status(String::from_utf8(vec![0xff]).unwrap_err().utf8_error()),
+            StatusCode::BAD_REQUEST
+        );
+        assert_eq!(
+            status(hyper::header::HeaderValue::from_str("\n").unwrap_err()),
+            StatusCode::INTERNAL_SERVER_ERROR
+        );
+    }
 }
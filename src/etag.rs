@@ -0,0 +1,7 @@
+/// A strong ETag derived from a 64-bit FNV-1a hash of the content.
+pub(crate) fn etag(data: &[u8]) -> String {
+    let hash = data.iter().fold(0xcbf2_9ce4_8422_2325u64, |hash, b| {
+        (hash ^ u64::from(*b)).wrapping_mul(0x0100_0000_01b3)
+    });
+    format!("\"{:016x}\"", hash)
+}
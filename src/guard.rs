@@ -0,0 +1,320 @@
+use std::sync::Arc;
+
+use crate::{HttpError, Middleware, Next, Request, Response, ResponseFuture};
+use futures::future;
+use hyper::StatusCode;
+
+/// An authenticated user or client, as put into the request extensions by e.g. `basic_auth` or
+/// `jwt`. Implement it for the principal type to use `require`.
+pub trait Principal: Send + Sync + 'static {
+    fn has_role(&self, role: &str) -> bool;
+
+    fn has_permission(&self, permission: &str) -> bool {
+        let _ = permission;
+        false
+    }
+}
+
+/// What a principal needs to be granted access. Strings convert into roles.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Requirement {
+    Role(String),
+    Permission(String),
+    AnyOf(Vec<Requirement>),
+    AllOf(Vec<Requirement>),
+}
+
+impl Requirement {
+    pub fn is_met_by<P: Principal + ?Sized>(&self, principal: &P) -> bool {
+        match *self {
+            Requirement::Role(ref role) => principal.has_role(role),
+            Requirement::Permission(ref permission) => principal.has_permission(permission),
+            Requirement::AnyOf(ref requirements) => {
+                requirements.iter().any(|r| r.is_met_by(principal))
+            }
+            // fail closed, an empty list is most likely a mistake
+            Requirement::AllOf(ref requirements) => {
+                !requirements.is_empty() && requirements.iter().all(|r| r.is_met_by(principal))
+            }
+        }
+    }
+}
+
+impl<'a> From<&'a str> for Requirement {
+    fn from(role: &'a str) -> Self {
+        Requirement::Role(role.to_owned())
+    }
+}
+
+impl From<String> for Requirement {
+    fn from(role: String) -> Self {
+        Requirement::Role(role)
+    }
+}
+
+pub fn role(role: &str) -> Requirement {
+    Requirement::Role(role.to_owned())
+}
+
+pub fn permission(permission: &str) -> Requirement {
+    Requirement::Permission(permission.to_owned())
+}
+
+/// Met if at least one of `requirements` is met, e.g. `any_of(["admin", "ops"])`. Panics if
+/// `requirements` is empty.
+pub fn any_of<I, R>(requirements: I) -> Requirement
+where
+    I: IntoIterator<Item = R>,
+    R: Into<Requirement>,
+{
+    Requirement::AnyOf(non_empty("any_of", requirements))
+}
+
+/// Met if all of `requirements` are met, e.g. `all_of([role("ops"), permission("deploy")])`.
+/// Panics if `requirements` is empty.
+pub fn all_of<I, R>(requirements: I) -> Requirement
+where
+    I: IntoIterator<Item = R>,
+    R: Into<Requirement>,
+{
+    Requirement::AllOf(non_empty("all_of", requirements))
+}
+
+fn non_empty<I, R>(name: &str, requirements: I) -> Vec<Requirement>
+where
+    I: IntoIterator<Item = R>,
+    R: Into<Requirement>,
+{
+    let requirements = requirements.into_iter().map(Into::into).collect::<Vec<_>>();
+    assert!(
+        !requirements.is_empty(),
+        "{} requires at least one requirement",
+        name
+    );
+    requirements
+}
+
+type Check<P> = dyn Fn(&P, &Request) -> bool + Send + Sync;
+
+pub struct Guard<P> {
+    check: Arc<Check<P>>,
+}
+
+/// Only lets requests through whose principal of type `P` meets `requirement`, e.g.
+/// `require::<User>(any_of(["admin", "ops"]))`. Other requests, including those without a
+/// principal, are answered with `403 Forbidden`. Add an authentication middleware before to
+/// challenge unauthenticated clients with `401 Unauthorized`.
+///
+/// Add it to an app before the middlewares it should protect, which can also be a mounted app to
+/// protect a prefix, or wrap a single middleware with `Guard::then`.
+pub fn require<P: Principal>(requirement: Requirement) -> Guard<P> {
+    Guard {
+        check: Arc::new(move |principal: &P, _: &Request| requirement.is_met_by(principal)),
+    }
+}
+
+/// Like `require`, but lets `check` decide whether the principal is granted access, e.g. to only
+/// allow users to access their own resources.
+pub fn require_fn<P, F>(check: F) -> Guard<P>
+where
+    F: Fn(&P, &Request) -> bool + Send + Sync + 'static,
+{
+    Guard {
+        check: Arc::new(check),
+    }
+}
+
+impl<P> Clone for Guard<P> {
+    fn clone(&self) -> Self {
+        Guard {
+            check: self.check.clone(),
+        }
+    }
+}
+
+impl<P> Guard<P>
+where
+    P: Send + Sync + 'static,
+{
+    /// Only calls `middleware` for requests that pass the guard.
+    pub fn then<M>(self, middleware: M) -> Guarded<P, M> {
+        Guarded {
+            guard: self,
+            middleware,
+        }
+    }
+
+    fn authorize(&self, req: &Request) -> Result<(), HttpError> {
+        match req.extensions().get::<P>() {
+            Some(principal) if (self.check)(principal, req) => Ok(()),
+            _ => Err(HttpError::Status(StatusCode::FORBIDDEN)),
+        }
+    }
+}
+
+impl<S, P> Middleware<S> for Guard<P>
+where
+    P: Send + Sync + 'static,
+{
+    fn handle(&self, req: Request, res: Response, state: S, next: Next<S>) -> ResponseFuture {
+        match self.authorize(&req) {
+            Ok(()) => next(req, res, state),
+            Err(err) => Box::new(future::err(err)),
+        }
+    }
+}
+
+pub struct Guarded<P, M> {
+    guard: Guard<P>,
+    middleware: M,
+}
+
+impl<S, P, M> Middleware<S> for Guarded<P, M>
+where
+    P: Send + Sync + 'static,
+    M: Middleware<S>,
+{
+    fn handle(&self, req: Request, res: Response, state: S, next: Next<S>) -> ResponseFuture {
+        match self.guard.authorize(&req) {
+            Ok(()) => self.middleware.handle(req, res, state, next),
+            Err(err) => Box::new(future::err(err)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Principal, Requirement};
+    use crate::{
+        all_of, any_of, default_fallback, mount, permission, require, require_fn, role, App,
+        HttpError, Next, Request, Response,
+    };
+    use futures::Future;
+    use hyper::{Body, StatusCode};
+
+    struct User {
+        name: &'static str,
+        roles: Vec<&'static str>,
+        permissions: Vec<&'static str>,
+    }
+
+    impl Principal for User {
+        fn has_role(&self, role: &str) -> bool {
+            self.roles.contains(&role)
+        }
+
+        fn has_permission(&self, permission: &str) -> bool {
+            self.permissions.contains(&permission)
+        }
+    }
+
+    fn call(app: &App<()>, path: &str, user: Option<User>) -> StatusCode {
+        let mut req = hyper::Request::get(path).body(Body::empty()).unwrap();
+        if let Some(user) = user {
+            req.extensions_mut().insert(user);
+        }
+        match app
+            .execute(req, Response::new(), (), default_fallback)
+            .wait()
+        {
+            Ok(res) => res.status(),
+            Err(err) => err.status(),
+        }
+    }
+
+    fn user(roles: Vec<&'static str>, permissions: Vec<&'static str>) -> Option<User> {
+        Some(User {
+            name: "alice",
+            roles,
+            permissions,
+        })
+    }
+
+    fn ok(
+        _req: Request,
+        mut res: Response,
+        _state: (),
+        _next: Next<()>,
+    ) -> Result<Response, HttpError> {
+        res.status(StatusCode::OK);
+        Ok(res)
+    }
+
+    #[test]
+    fn requirements() {
+        let admin = user(vec!["admin"], vec![]);
+        assert!(role("admin").is_met_by(admin.as_ref().unwrap()));
+        assert!(any_of(["admin", "ops"]).is_met_by(admin.as_ref().unwrap()));
+        assert!(!all_of(["admin", "ops"]).is_met_by(admin.as_ref().unwrap()));
+
+        let deployer = user(vec!["ops"], vec!["deploy"]);
+        let requirement = all_of([role("ops"), permission("deploy")]);
+        assert!(requirement.is_met_by(deployer.as_ref().unwrap()));
+        assert!(!requirement.is_met_by(user(vec!["ops"], vec![]).as_ref().unwrap()));
+
+        assert!(!Requirement::AllOf(vec![]).is_met_by(deployer.as_ref().unwrap()));
+    }
+
+    #[test]
+    #[should_panic(expected = "all_of requires at least one requirement")]
+    fn rejects_empty_requirements() {
+        all_of(Vec::<Requirement>::new());
+    }
+
+    #[test]
+    fn guards_prefixes_and_middlewares() {
+        let mut admin = App::new();
+        admin.add(require::<User>(any_of(["admin", "ops"])));
+        admin.add(ok);
+
+        let mut app = App::new();
+        app.add(mount("/admin", admin.build()));
+        app.add(mount(
+            "/deploy",
+            require::<User>(permission("deploy")).then(ok),
+        ));
+        app.add(ok);
+        let app = app.build();
+
+        assert_eq!(
+            call(&app, "/admin", user(vec!["ops"], vec![])),
+            StatusCode::OK
+        );
+        assert_eq!(
+            call(&app, "/admin", user(vec!["dev"], vec![])),
+            StatusCode::FORBIDDEN
+        );
+        assert_eq!(call(&app, "/admin", None), StatusCode::FORBIDDEN);
+
+        assert_eq!(
+            call(&app, "/deploy", user(vec![], vec!["deploy"])),
+            StatusCode::OK
+        );
+        assert_eq!(
+            call(&app, "/deploy", user(vec!["admin"], vec![])),
+            StatusCode::FORBIDDEN
+        );
+
+        // unguarded
+        assert_eq!(call(&app, "/", None), StatusCode::OK);
+    }
+
+    #[test]
+    fn custom_decisions() {
+        let mut app = App::new();
+        app.add(require_fn(|user: &User, req: &Request| {
+            req.uri().path() == format!("/users/{}", user.name)
+        }));
+        app.add(ok);
+        let app = app.build();
+
+        assert_eq!(
+            call(&app, "/users/alice", user(vec![], vec![])),
+            StatusCode::OK
+        );
+        assert_eq!(
+            call(&app, "/users/bob", user(vec![], vec![])),
+            StatusCode::FORBIDDEN
+        );
+    }
+}
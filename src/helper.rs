@@ -1,9 +1,15 @@
 use std::str::FromStr;
 
-use crate::{HttpError, Middleware, Next, Request, Response, ResponseFuture};
+use std::sync::Arc;
+
+use crate::{HttpError, IntoResponse, Middleware, Next, Request, Response, ResponseFuture};
+#[cfg(feature = "json")]
 use hyper::body::Body;
+#[cfg(feature = "json")]
 use hyper::header::{HeaderValue, CONTENT_TYPE};
-use hyper::{StatusCode, Uri};
+#[cfg(feature = "json")]
+use hyper::StatusCode;
+use hyper::Uri;
 
 #[macro_export]
 macro_rules! combine {
@@ -19,6 +25,11 @@ macro_rules! combine {
     };
 }
 
+/// The URI of the request before it was rewritten by the first `mount` it passed, available
+/// through the request extensions.
+#[derive(Debug, Clone)]
+pub struct OriginalUri(pub Uri);
+
 pub struct MountMiddleware<M> {
     path: String,
     middleware: M,
@@ -31,31 +42,23 @@ where
 {
     fn handle(&self, mut req: Request, res: Response, ctx: S, next: Next<S>) -> ResponseFuture {
         if req.uri().path().starts_with(self.path.as_str()) {
+            #[cfg(debug_assertions)]
+            {
+                if let Some(trace) = crate::dev::Trace::of(&req) {
+                    trace.mount(&self.path);
+                }
+            }
             let uri_before = req.uri().clone();
+            if req.extensions().get::<OriginalUri>().is_none() {
+                req.extensions_mut().insert(OriginalUri(uri_before.clone()));
+            }
 
-            // TODO: extend hyper to not have to create a URI from string
             let new_uri = {
-                let mut s = String::from("");
-                if let Some(scheme) = uri_before.scheme_part() {
-                    s += scheme.as_str();
-                    s += "://";
-                }
-                if let Some(authority) = uri_before.authority_part() {
-                    s += authority.as_str();
-                }
-
                 let (_, mut new_path) = uri_before.path().split_at(self.path.len());
                 if new_path.is_empty() {
                     new_path = "/";
                 }
-                s += new_path;
-
-                if let Some(query) = uri_before.query() {
-                    s += "?";
-                    s += query;
-                }
-                // TODO: does not contain fragment (because currently not exposed by hyper)
-                Uri::from_str(s.as_str()).unwrap()
+                replace_path(&uri_before, new_path)
             };
 
             *req.uri_mut() = new_uri;
@@ -75,6 +78,25 @@ where
     }
 }
 
+pub(crate) fn replace_path(uri: &Uri, path: &str) -> Uri {
+    // TODO: extend hyper to not have to create a URI from string
+    let mut s = String::from("");
+    if let Some(scheme) = uri.scheme_part() {
+        s += scheme.as_str();
+        s += "://";
+    }
+    if let Some(authority) = uri.authority_part() {
+        s += authority.as_str();
+    }
+    s += path;
+    if let Some(query) = uri.query() {
+        s += "?";
+        s += query;
+    }
+    // TODO: does not contain fragment (because currently not exposed by hyper)
+    Uri::from_str(s.as_str()).unwrap()
+}
+
 pub fn mount<S, M: Middleware<S>>(path: &str, mw: M) -> MountMiddleware<M> {
     MountMiddleware {
         path: path.to_owned(),
@@ -82,11 +104,53 @@ pub fn mount<S, M: Middleware<S>>(path: &str, mw: M) -> MountMiddleware<M> {
     }
 }
 
+impl<M> MountMiddleware<M> {
+    /// Handles requests below the mount path that the mounted middleware passed on, instead of
+    /// letting them fall through to the middlewares after the mount. The fallback is terminal:
+    /// it cannot pass requests on, so the middlewares after the mount are never called for
+    /// requests below the mount path. Leave it out to let those requests fall through.
+    pub fn fallback<F>(self, handler: F) -> MountMiddleware<MountFallback<M, F>> {
+        MountMiddleware {
+            path: self.path,
+            middleware: MountFallback {
+                middleware: self.middleware,
+                fallback: Arc::new(handler),
+            },
+        }
+    }
+}
+
+pub struct MountFallback<M, F> {
+    middleware: M,
+    fallback: Arc<F>,
+}
+
+impl<S, M, F, B> Middleware<S> for MountFallback<M, F>
+where
+    S: 'static,
+    M: Middleware<S>,
+    F: Fn(Request, Response, S) -> B + Send + Sync + 'static,
+    B: IntoResponse<HttpError>,
+{
+    fn handle(&self, req: Request, res: Response, ctx: S, _next: Next<S>) -> ResponseFuture {
+        // the fallback replaces the rest of the chain, see `MountMiddleware::fallback`
+        let fallback = self.fallback.clone();
+        self.middleware.handle(
+            req,
+            res,
+            ctx,
+            Next::new(move |req, res, ctx| fallback(req, res, ctx).into_response()),
+        )
+    }
+}
+
 #[cfg(feature = "json")]
 impl From<::serde_json::Error> for HttpError {
     fn from(err: ::serde_json::Error) -> Self {
-        eprintln!("Error converting to json: {}", err);
-        HttpError::Status(StatusCode::BAD_REQUEST)
+        HttpError::Internal(Box::new(crate::Internal::with_status(
+            StatusCode::BAD_REQUEST,
+            err,
+        )))
     }
 }
 
@@ -108,10 +172,10 @@ where
 
 #[cfg(test)]
 mod tests {
-    use futures::Future;
-    use hyper::{Body, Request, Response};
+    use crate::{default_fallback, mount, App, HttpError, Next};
+    use futures::{Future, Stream};
+    use hyper::{Body, Request, Response, StatusCode};
     use std::sync::{Arc, Mutex};
-    use {default_fallback, mount, App, HttpError};
 
     #[test]
     fn combine() {
@@ -145,7 +209,7 @@ mod tests {
             .wait()
             .unwrap();
 
-        assert_eq!(*called.lock().unwrap(), true);
+        assert!(*called.lock().unwrap());
     }
 
     #[test]
@@ -171,6 +235,45 @@ mod tests {
             .wait()
             .unwrap();
 
-        assert_eq!(*called.lock().unwrap(), false);
+        assert!(!*called.lock().unwrap());
+    }
+
+    #[test]
+    fn mount_fallback() {
+        let mut api = App::new();
+        api.add(|req: Request<Body>, res, state, next: Next<()>| {
+            if req.uri().path() == "/users" {
+                Box::new(futures::future::ok(Response::new(Body::from("users"))))
+            } else {
+                next(req, res, state)
+            }
+        });
+
+        let mut app = App::new();
+        app.add(mount("/api", api.build()).fallback(
+            |req: Request<Body>, mut res: crate::Response, _| {
+                res.status(StatusCode::NOT_FOUND)
+                    .body(Body::from(format!("no api at {}", req.uri().path())))
+            },
+        ));
+        app.add(|_, res, _, _| Ok::<_, HttpError>(res));
+        let app = app.build();
+
+        let call = |path: &str| {
+            let req = Request::get(path).body(Body::empty()).unwrap();
+            let res = app
+                .execute(req, Response::builder(), (), default_fallback)
+                .wait()
+                .unwrap();
+            let status = res.status();
+            (status, res.into_body().concat2().wait().unwrap().to_vec())
+        };
+
+        assert_eq!(call("/api/users"), (StatusCode::OK, b"users".to_vec()));
+        assert_eq!(
+            call("/api/posts"),
+            (StatusCode::NOT_FOUND, b"no api at /posts".to_vec())
+        );
+        assert_eq!(call("/posts"), (StatusCode::OK, Vec::new()));
     }
 }
@@ -0,0 +1,155 @@
+use crate::helper::replace_path;
+use crate::{Middleware, Next, Request, Response, ResponseFuture};
+use hyper::header::ACCEPT;
+use hyper::Method;
+
+pub struct HistoryFallback<M> {
+    index: String,
+    exclude: Vec<String>,
+    middleware: M,
+}
+
+/// Rewrites `GET` requests for HTML documents to `/index.html` and passes them to `middleware`,
+/// which is expected to serve that document (e.g. `static_files`). Add it as the last middleware
+/// so that it only catches requests nothing else has responded to.
+pub fn history_fallback<S, M: Middleware<S>>(mw: M) -> HistoryFallback<M> {
+    HistoryFallback {
+        index: "/index.html".to_owned(),
+        exclude: Vec::new(),
+        middleware: mw,
+    }
+}
+
+impl<M> HistoryFallback<M> {
+    /// The path requests are rewritten to (defaults to `/index.html`).
+    pub fn index(mut self, path: &str) -> Self {
+        self.index = path.to_owned();
+        self
+    }
+
+    /// Do not rewrite requests for `prefix` and paths below it, e.g. `/api`.
+    pub fn exclude(mut self, prefix: &str) -> Self {
+        self.exclude.push(prefix.trim_end_matches('/').to_owned());
+        self
+    }
+
+    fn applies(&self, req: &Request) -> bool {
+        if req.method() != Method::GET && req.method() != Method::HEAD {
+            return false;
+        }
+
+        let path = req.uri().path();
+        if self.exclude.iter().any(|prefix| {
+            path.starts_with(prefix.as_str())
+                && (path.len() == prefix.len() || path[prefix.len()..].starts_with('/'))
+        }) {
+            return false;
+        }
+
+        req.headers()
+            .get_all(ACCEPT)
+            .iter()
+            .filter_map(|v| v.to_str().ok())
+            .flat_map(|v| v.split(','))
+            .any(|range| {
+                let mut params = range.split(';');
+                params.next().map(str::trim) == Some("text/html")
+                    && !params.any(|p| {
+                        let p = p.trim();
+                        p.starts_with("q=") && p[2..].trim().parse::<f32>().ok() == Some(0.0)
+                    })
+            })
+    }
+}
+
+impl<S, M> Middleware<S> for HistoryFallback<M>
+where
+    S: 'static,
+    M: Middleware<S>,
+{
+    fn handle(&self, mut req: Request, res: Response, state: S, next: Next<S>) -> ResponseFuture {
+        if !self.applies(&req) {
+            return next(req, res, state);
+        }
+
+        let new_uri = replace_path(req.uri(), &self.index);
+        *req.uri_mut() = new_uri;
+        self.middleware.handle(req, res, state, next)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{default_fallback, history_fallback, App, HttpError, Request};
+    use futures::Future;
+    use hyper::header::ACCEPT;
+    use hyper::{Body, StatusCode};
+    use std::sync::{Arc, Mutex};
+
+    fn execute(req: Request) -> (StatusCode, Option<String>) {
+        let served = Arc::new(Mutex::new(None));
+        let mut app = App::new();
+        {
+            let served = served.clone();
+            app.add(
+                history_fallback(move |req: Request, res, _: (), _| {
+                    *served.lock().unwrap() = Some(req.uri().to_string());
+                    Ok::<_, HttpError>(res)
+                })
+                .exclude("/api"),
+            );
+        }
+        let res = app
+            .build()
+            .execute(req, crate::Response::new(), (), default_fallback)
+            .wait()
+            .unwrap();
+        let served = served.lock().unwrap().clone();
+        (res.status(), served)
+    }
+
+    #[test]
+    fn rewrites_html_requests() {
+        let req = hyper::Request::get("/users/42?tab=1")
+            .header(ACCEPT, "text/html,application/xhtml+xml,*/*;q=0.8")
+            .body(Body::empty())
+            .unwrap();
+        assert_eq!(
+            execute(req),
+            (StatusCode::OK, Some("/index.html?tab=1".to_owned()))
+        );
+    }
+
+    #[test]
+    fn keeps_excluded_prefixes() {
+        let req = hyper::Request::get("/api/users")
+            .header(ACCEPT, "text/html")
+            .body(Body::empty())
+            .unwrap();
+        assert_eq!(execute(req), (StatusCode::NOT_FOUND, None));
+
+        let req = hyper::Request::get("/apiary")
+            .header(ACCEPT, "text/html")
+            .body(Body::empty())
+            .unwrap();
+        assert_eq!(
+            execute(req),
+            (StatusCode::OK, Some("/index.html".to_owned()))
+        );
+    }
+
+    #[test]
+    fn ignores_non_html_requests() {
+        let req = hyper::Request::get("/users/42")
+            .header(ACCEPT, "application/json")
+            .body(Body::empty())
+            .unwrap();
+        assert_eq!(execute(req), (StatusCode::NOT_FOUND, None));
+
+        let req = hyper::Request::post("/users/42")
+            .header(ACCEPT, "text/html")
+            .body(Body::empty())
+            .unwrap();
+        assert_eq!(execute(req), (StatusCode::NOT_FOUND, None));
+    }
+}
@@ -1,27 +1,99 @@
 #![feature(unboxed_closures, fn_traits)]
 
+extern crate base64;
+extern crate brotli;
+#[cfg(feature = "session")]
+extern crate cookie;
+extern crate flate2;
 extern crate futures;
 extern crate http;
+extern crate httpdate;
 extern crate hyper;
+#[cfg(feature = "jwt")]
+extern crate jsonwebtoken;
+extern crate log;
+extern crate mime_guess;
+extern crate percent_encoding;
+#[cfg(feature = "session")]
+extern crate rand;
+#[cfg(feature = "regex")]
+extern crate regex;
 #[cfg(feature = "json")]
 extern crate serde;
 #[cfg(feature = "json")]
 extern crate serde_json;
+extern crate tokio_timer;
 
+use std::error::Error as StdError;
+use std::net::SocketAddr;
 use std::panic::AssertUnwindSafe;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
 
 use futures::{future, Future, IntoFuture};
-use hyper::header::{HeaderValue, CONTENT_TYPE};
+use hyper::header::{HeaderMap, HeaderValue, CONTENT_TYPE};
 use hyper::service::Service;
 use hyper::StatusCode;
 pub use hyper::{Body, Server};
 
+mod access_log;
+pub use access_log::{access_log, AccessLog, LogFormat};
+mod auth;
+pub use auth::{basic_auth, bearer_auth, BasicAuth, BearerAuth, Credentials, VerifyFuture};
+mod compression;
+pub use compression::{compression, Compression};
+mod conditional;
+pub use conditional::{check_preconditions, conditional, Conditional};
+mod cors;
+pub use cors::{cors, Cors};
+#[cfg(feature = "session")]
+mod csrf;
+#[cfg(feature = "session")]
+pub use csrf::{csrf, Csrf, CsrfToken};
+mod decompression;
+pub use decompression::{decompression, Decompression};
+#[cfg(debug_assertions)]
+mod dev;
+pub mod embed;
+pub use embed::{embedded, Embedded, EmbeddedDir, EmbeddedFile};
+mod encoding;
+pub use encoding::Encoding;
 pub mod error;
-pub use error::HttpError;
+#[cfg(feature = "json")]
+pub use error::Problem;
+pub use error::{Context, HttpError, Internal};
+mod etag;
+mod guard;
+pub use guard::{
+    all_of, any_of, permission, require, require_fn, role, Guard, Guarded, Principal, Requirement,
+};
 #[macro_use]
 mod helper;
 pub use helper::*;
+mod history;
+pub use history::{history_fallback, HistoryFallback};
+#[cfg(feature = "jwt")]
+mod jwt;
+#[cfg(feature = "jwt")]
+pub use jwt::{jwt, Jwt, JwtKey};
+mod panic;
+pub use panic::Panic;
+#[cfg(feature = "json")]
+mod problem;
+mod rate_limit;
+pub use rate_limit::{rate_limit, Decision, Limit, MemoryStore, RateLimit, Store, StoreFuture};
+mod request_id;
+pub use request_id::{request_id, RequestId, RequestIds};
+#[cfg(feature = "session")]
+mod session;
+#[cfg(feature = "session")]
+pub use session::{
+    sessions, CookieSessionStore, FileSessionStore, MemorySessionStore, Record, SameSite, Session,
+    SessionFuture, SessionStore, Sessions,
+};
+mod static_files;
+pub use static_files::{static_files, StaticFiles};
+mod timeout;
+pub use timeout::{timeout, Timeout};
 
 pub type Request = hyper::Request<Body>;
 pub type Response = http::response::Builder;
@@ -32,6 +104,13 @@ pub type ResponseResult<E = HttpError> = Result<HttpResponse, E>;
 // pub type ResponseFuture<E = HttpError> = Future<Item = HttpResponse, Error = E> + Send;
 pub type ResponseFuture<E = HttpError> = Box<dyn Future<Item = HttpResponse, Error = E> + Send>;
 
+pub type ErrorRenderer =
+    dyn Fn(HttpError, &http::request::Parts) -> Result<HttpResponse, http::Error> + Send + Sync;
+pub type ErrorHook = dyn Fn(&HttpError, &http::request::Parts) + Send + Sync;
+pub type Fallback<S> = dyn Fn(Request, Response, S) -> ResponseFuture + Send + Sync;
+pub type PanicHook =
+    dyn Fn(&Panic, &http::request::Parts) -> Result<HttpResponse, http::Error> + Send + Sync;
+
 pub trait Middleware<S>: Send + Sync {
     fn handle(&self, req: Request, res: Response, state: S, state: Next<S>) -> ResponseFuture;
 }
@@ -42,6 +121,11 @@ pub trait IntoResponse<E = HttpError> {
 
 pub struct AppBuilder<S> {
     middlewares: Vec<Box<dyn Middleware<S>>>,
+    error_renderer: Option<Arc<ErrorRenderer>>,
+    error_hook: Option<Arc<ErrorHook>>,
+    panic_hook: Option<Arc<PanicHook>>,
+    dev_error_page: bool,
+    fallback: Option<Arc<Fallback<S>>>,
 }
 
 pub struct App<S>
@@ -49,6 +133,12 @@ where
     S: Send,
 {
     middlewares: Arc<Vec<Box<dyn Middleware<S>>>>,
+    error_renderer: Option<Arc<ErrorRenderer>>,
+    error_hook: Option<Arc<ErrorHook>>,
+    panic_hook: Option<Arc<PanicHook>>,
+    #[cfg_attr(not(debug_assertions), allow(dead_code))]
+    dev_error_page: bool,
+    fallback: Option<Arc<Fallback<S>>>,
 }
 
 pub struct Next<S> {
@@ -64,6 +154,60 @@ where
 {
     app: App<S>,
     state_factory: Arc<F>,
+    remote_addr: Option<SocketAddr>,
+}
+
+/// The address of the client, available through the request extensions if it has been provided
+/// with `Serve::remote_addr`.
+#[derive(Debug, Clone, Copy)]
+pub struct RemoteAddr(pub SocketAddr);
+
+/// Headers for the response `Serve` renders if the request ends in an error or a panic, available
+/// through the request extensions. Lets middlewares add headers, e.g. for CORS, to responses of
+/// errors that do not carry a response themselves.
+#[derive(Debug, Clone, Default)]
+pub struct ErrorHeaders(Arc<Mutex<HeaderMap>>);
+
+impl ErrorHeaders {
+    /// Calls `add` with the headers of the response of `result`, or if it is an error without a
+    /// response, with the headers of `errors` to be added once the error has been rendered.
+    pub fn add<F>(
+        errors: Option<&ErrorHeaders>,
+        result: Result<HttpResponse, HttpError>,
+        add: F,
+    ) -> Result<HttpResponse, HttpError>
+    where
+        F: FnOnce(&mut HeaderMap),
+    {
+        match result {
+            Ok(mut res) => {
+                add(res.headers_mut());
+                Ok(res)
+            }
+            Err(HttpError::Response(mut res)) => {
+                add(res.headers_mut());
+                Err(HttpError::Response(res))
+            }
+            Err(err) => {
+                if let Some(errors) = errors {
+                    add(&mut errors.0.lock().unwrap());
+                }
+                Err(err)
+            }
+        }
+    }
+
+    fn apply(&self, mut res: HttpResponse) -> HttpResponse {
+        let headers = std::mem::take(&mut *self.0.lock().unwrap());
+        let mut name = None;
+        for (key, value) in headers {
+            name = key.or(name);
+            if let Some(ref name) = name {
+                res.headers_mut().append(name, value);
+            }
+        }
+        res
+    }
 }
 
 fn default_fallback<S, E>(_req: Request, _res: Response, _state: S) -> ResponseFuture<E>
@@ -97,9 +241,70 @@ where
         self.middlewares.push(Box::new(middleware));
     }
 
+    /// Handles requests that no middleware responded to, instead of replying with an empty
+    /// `404 Not Found`. Only applies to the app that is served, apps used as middleware pass such
+    /// requests on to the next middleware of the outer app.
+    pub fn fallback<F, B>(&mut self, handler: F)
+    where
+        F: Fn(Request, Response, S) -> B + Send + Sync + 'static,
+        B: IntoResponse<HttpError>,
+    {
+        self.fallback = Some(Arc::new(move |req, res, state| {
+            handler(req, res, state).into_response()
+        }));
+    }
+
+    /// Renders errors that reach the server into responses, instead of
+    /// `HttpError::into_response`. It receives the request's method, URI, version and headers.
+    /// Only applies to the app that is served, not to apps used as middleware.
+    pub fn error_renderer<F>(&mut self, renderer: F)
+    where
+        F: Fn(HttpError, &http::request::Parts) -> Result<HttpResponse, http::Error>
+            + Send
+            + Sync
+            + 'static,
+    {
+        self.error_renderer = Some(Arc::new(renderer));
+    }
+
+    /// Gets called with every error that reaches the server, before it is rendered. Defaults to
+    /// printing errors that result in an internal server error to stderr and logging internal
+    /// errors with a client error status at the info level.
+    pub fn on_error<F>(&mut self, hook: F)
+    where
+        F: Fn(&HttpError, &http::request::Parts) + Send + Sync + 'static,
+    {
+        self.error_hook = Some(Arc::new(hook));
+    }
+
+    /// Renders panics that occurred while handling a request into responses. Defaults to printing
+    /// the panic to stderr and responding with an empty internal server error.
+    pub fn on_panic<F>(&mut self, hook: F)
+    where
+        F: Fn(&Panic, &http::request::Parts) -> Result<HttpResponse, http::Error>
+            + Send
+            + Sync
+            + 'static,
+    {
+        self.panic_hook = Some(Arc::new(hook));
+    }
+
+    /// Renders errors that point to a bug and panics as an HTML page containing the error chain,
+    /// the backtrace, the request and the middleware that failed. Takes precedence over the error
+    /// renderer and panic hook. Has no effect in release builds, the page is not even compiled
+    /// into those.
+    pub fn dev_error_page(&mut self, enabled: bool) {
+        self.dev_error_page = enabled;
+    }
+
     pub fn build(self) -> App<S> {
         App {
             middlewares: Arc::new(self.middlewares),
+            error_renderer: self.error_renderer,
+            error_hook: self.error_hook,
+            panic_hook: self.panic_hook,
+            dev_error_page: self.dev_error_page,
+            fallback: self.fallback,
         }
     }
 }
@@ -108,6 +313,11 @@ impl<S> Default for AppBuilder<S> {
     fn default() -> Self {
         AppBuilder {
             middlewares: Vec::new(),
+            error_renderer: None,
+            error_hook: None,
+            panic_hook: None,
+            dev_error_page: false,
+            fallback: None,
         }
     }
 }
@@ -116,6 +326,7 @@ impl<S> App<S>
 where
     S: Send,
 {
+    #[allow(clippy::new_ret_no_self)]
     pub fn new() -> AppBuilder<S> {
         AppBuilder::default()
     }
@@ -136,13 +347,137 @@ where
     where
         F: Fn() -> S,
     {
+        panic::install_hook();
         Serve {
             app: self.clone(),
             state_factory: Arc::new(state),
+            remote_addr: None,
         }
     }
 }
 
+impl<S, F> Serve<S, F>
+where
+    F: Fn() -> S,
+    S: Send,
+{
+    /// Makes the address of the client available to the middlewares as `RemoteAddr`, e.g.
+    /// `app.serve(|| ()).remote_addr(conn.remote_addr())` inside of `make_service_fn`.
+    pub fn remote_addr(mut self, addr: SocketAddr) -> Self {
+        self.remote_addr = Some(addr);
+        self
+    }
+}
+
+impl<S> App<S>
+where
+    S: Send,
+{
+    /// Whether errors or panics are passed to hooks or the developer error page, which get to see
+    /// the request headers.
+    fn inspects_failures(&self) -> bool {
+        self.error_renderer.is_some()
+            || self.error_hook.is_some()
+            || self.panic_hook.is_some()
+            || (cfg!(debug_assertions) && self.dev_error_page)
+    }
+
+    fn report_error(&self, err: &HttpError, head: &http::request::Parts) {
+        match self.error_hook {
+            Some(ref hook) => hook(err, head),
+            None => match *err {
+                HttpError::Http(ref err) => {
+                    eprintln!("{}Error with http: {}", log_prefix(head), err)
+                }
+                HttpError::Internal(ref err) => {
+                    let mut line = format!("{}Internal error: {}", log_prefix(head), err);
+                    let mut source = err.source();
+                    while let Some(err) = source {
+                        line.push_str(&format!(": {}", err));
+                        source = err.source();
+                    }
+                    // client errors, e.g. malformed input, are expected and only logged
+                    if err.status().is_server_error() {
+                        eprintln!("{}", line);
+                    } else {
+                        log::info!("{}", line);
+                    }
+                }
+                _ => {}
+            },
+        }
+    }
+
+    /// Turns `err` into a response. Never fails, an error that cannot be rendered ends in an
+    /// empty internal server error.
+    fn render_error(&self, err: HttpError, head: &http::request::Parts) -> HttpResponse {
+        self.report_error(&err, head);
+
+        #[cfg(debug_assertions)]
+        {
+            if head.extensions.get::<Arc<dev::Trace>>().is_some() && dev::applies(&err) {
+                return dev::render_error(&err, head);
+            }
+        }
+
+        let err = match err {
+            HttpError::Http(_) => HttpError::Status(StatusCode::INTERNAL_SERVER_ERROR),
+            err => err,
+        };
+        let res = match self.error_renderer {
+            Some(ref renderer) => renderer(err, head),
+            None => err.into_response(),
+        };
+        res.unwrap_or_else(|err| {
+            self.report_error(&HttpError::Http(err), head);
+            internal_server_error()
+        })
+    }
+
+    fn render_panic(&self, panic: &Panic, head: &http::request::Parts) -> HttpResponse {
+        #[cfg(debug_assertions)]
+        {
+            if head.extensions.get::<Arc<dev::Trace>>().is_some() {
+                return dev::render_panic(panic, head);
+            }
+        }
+
+        match self.panic_hook {
+            Some(ref hook) => hook(panic, head).unwrap_or_else(|err| {
+                self.report_error(&HttpError::Http(err), head);
+                internal_server_error()
+            }),
+            None => {
+                eprintln!(
+                    "{}Panic while handling {} {}: {}",
+                    log_prefix(head),
+                    head.method,
+                    head.uri,
+                    panic
+                );
+                if let Some(ref backtrace) = panic.backtrace {
+                    eprintln!("{}", backtrace);
+                }
+                internal_server_error()
+            }
+        }
+    }
+}
+
+/// Prefixes the default error output with the request ID, if there is one.
+fn log_prefix(head: &http::request::Parts) -> String {
+    match head.extensions.get::<RequestId>() {
+        Some(id) => format!("[{}] ", id),
+        None => String::new(),
+    }
+}
+
+fn internal_server_error() -> HttpResponse {
+    let mut res = HttpResponse::new(Body::empty());
+    *res.status_mut() = StatusCode::INTERNAL_SERVER_ERROR;
+    res
+}
+
 impl<S> Middleware<S> for App<S>
 where
     S: Send + 'static,
@@ -159,6 +494,11 @@ where
     fn clone(&self) -> Self {
         App {
             middlewares: self.middlewares.clone(),
+            error_renderer: self.error_renderer.clone(),
+            error_hook: self.error_hook.clone(),
+            panic_hook: self.panic_hook.clone(),
+            dev_error_page: self.dev_error_page,
+            fallback: self.fallback.clone(),
         }
     }
 }
@@ -189,9 +529,16 @@ impl<S> Next<S> {
         let middlewares = self.middlewares.clone();
         if let Some(mw) = middlewares.get(self.pos) {
             self.pos += 1;
+            #[cfg(debug_assertions)]
+            {
+                if let Some(trace) = dev::Trace::of(&req) {
+                    let pos = self.pos - 1;
+                    return trace.middleware(pos, || mw.handle(req, res, state, self));
+                }
+            }
             mw.handle(req, res, state, self)
         } else {
-            return (self.finally)(req, res, state);
+            (self.finally)(req, res, state)
         }
     }
 }
@@ -210,23 +557,76 @@ where
     fn call(&mut self, req: hyper::Request<Self::ReqBody>) -> Self::Future {
         let state = (self.state_factory)();
         let app = self.app.clone();
-        let resp = AssertUnwindSafe(future::lazy(move || {
-            app.execute(req, Response::default(), state, default_fallback)
-                .or_else(|err| err.into_response())
-        }));
-        Box::new(resp.catch_unwind().then(|result| match result {
-            Ok(res) => res,
-            Err(_) => {
-                eprintln!("CATCH UNWIND");
-                Ok(Response::new()
-                    .status(StatusCode::INTERNAL_SERVER_ERROR)
-                    .body(Body::empty())
-                    .unwrap())
+        let (mut parts, body) = req.into_parts();
+        if let Some(addr) = self.remote_addr {
+            parts.extensions.insert(RemoteAddr(addr));
+        }
+        let slot = Arc::new(request_id::Slot::default());
+        parts.extensions.insert(slot.clone());
+        let errors = ErrorHeaders::default();
+        parts.extensions.insert(errors.clone());
+        let mut head = request_head(&parts, app.inspects_failures());
+        #[cfg(debug_assertions)]
+        {
+            if app.dev_error_page {
+                let trace = Arc::new(dev::Trace::default());
+                parts.extensions.insert(trace.clone());
+                head.extensions.insert(trace);
             }
+        }
+        let req = Request::from_parts(parts, body);
+        let resp =
+            {
+                let app = app.clone();
+                let fallback = app.fallback.clone();
+                AssertUnwindSafe(panic::capture(future::lazy(move || {
+                    app.execute(req, Response::default(), state, move |req, res, state| {
+                        match fallback {
+                            Some(fallback) => fallback(req, res, state),
+                            None => default_fallback(req, res, state),
+                        }
+                    })
+                })))
+            };
+        // catches panics of the middlewares and of the futures they return
+        Box::new(resp.catch_unwind().then(move |result| {
+            let id = slot.take();
+            if let Some((_, ref id)) = id {
+                head.extensions.insert(id.clone());
+            }
+            let res = match result {
+                Ok(Ok(res)) => return Ok(res),
+                // the error renderer and panic hook are user code as well
+                Ok(Err(err)) => {
+                    std::panic::catch_unwind(AssertUnwindSafe(|| app.render_error(err, &head)))
+                        .unwrap_or_else(|_| internal_server_error())
+                }
+                Err(payload) => {
+                    let panic = panic::take(payload);
+                    std::panic::catch_unwind(AssertUnwindSafe(|| app.render_panic(&panic, &head)))
+                        .unwrap_or_else(|_| internal_server_error())
+                }
+            };
+            let res = errors.apply(res);
+            Ok(request_id::echo(res, id))
         }))
     }
 }
 
+/// Copies the method, URI and version of the request head, so that they are still available after
+/// the request has been handed to the middlewares. The headers are only copied if `headers` is
+/// true, since they are only needed when errors and panics are passed to user code.
+fn request_head(parts: &http::request::Parts, headers: bool) -> http::request::Parts {
+    let mut req = http::Request::new(());
+    *req.method_mut() = parts.method.clone();
+    *req.uri_mut() = parts.uri.clone();
+    *req.version_mut() = parts.version;
+    if headers {
+        *req.headers_mut() = parts.headers.clone();
+    }
+    req.into_parts().0
+}
+
 // TODO: better/shorter name?
 pub trait IntoHttpResponse<E = HttpError> {
     fn into_http_response(self) -> ResponseResult<E>;
@@ -300,13 +700,13 @@ where
 
 #[cfg(test)]
 mod tests {
-    use futures::{Future, IntoFuture};
-    use hyper::{self, Body, StatusCode};
-    use std::sync::{Arc, Mutex};
-    use {
+    use crate::{
         default_fallback, App, HttpError, HttpResponse, IntoResponse, Middleware, Next as _Next,
         Request, Response, ResponseFuture,
     };
+    use futures::{Future, IntoFuture, Stream};
+    use hyper::{self, Body, StatusCode};
+    use std::sync::{Arc, Mutex};
 
     type Next = _Next<()>;
 
@@ -463,4 +863,171 @@ mod tests {
 
         assert_eq!(res.status(), StatusCode::FORBIDDEN);
     }
+
+    #[test]
+    fn fallback() {
+        use hyper::service::Service;
+
+        let mut app = App::new();
+        app.add(|req: Request, res, state, next: Next| next(req, res, state));
+        app.fallback(|req: Request, mut res: Response, _state| {
+            res.status(StatusCode::NOT_FOUND)
+                .body(Body::from(format!("{} not found", req.uri().path())))
+        });
+        let mut server = app.build().serve(|| ());
+
+        let req = hyper::Request::get("http://localhost/missing")
+            .body(Body::empty())
+            .unwrap();
+        let res = server.call(req).wait().unwrap();
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+        let body = res.into_body().concat2().wait().unwrap();
+        assert_eq!(&body[..], b"/missing not found");
+    }
+
+    #[test]
+    fn http_error_results_in_internal_server_error() {
+        use hyper::service::Service;
+
+        let reported = Arc::new(Mutex::new(Vec::new()));
+        let mut app = App::new();
+        app.add(move |_req, mut res: Response, _state, _next: Next| {
+            res.header("invalid header", "").body(Body::empty())
+        });
+        {
+            let reported = reported.clone();
+            app.on_error(move |err, req| {
+                let is_http = matches!(err, HttpError::Http(_));
+                reported
+                    .lock()
+                    .unwrap()
+                    .push((req.uri.to_string(), is_http));
+            });
+        }
+        let mut server = app.build().serve(|| ());
+
+        let req = hyper::Request::get("http://localhost/foo")
+            .body(Body::empty())
+            .unwrap();
+        let res = server.call(req).wait().unwrap();
+
+        assert_eq!(res.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        assert_eq!(
+            *reported.lock().unwrap(),
+            vec![("http://localhost/foo".to_owned(), true)]
+        );
+    }
+
+    #[test]
+    fn panic_hook() {
+        use hyper::service::Service;
+
+        let mut app = App::new();
+        app.add(|req: Request, res, state, next: Next| {
+            if req.uri().path() == "/sync" {
+                panic!("sync panic");
+            }
+            next(req, res, state)
+        });
+        app.add(|_req, res: Response, _state, _next: Next| {
+            futures::future::lazy(move || {
+                if true {
+                    panic!("async panic {}", 42);
+                }
+                Ok::<_, HttpError>(res)
+            })
+        });
+        app.on_panic(|panic, req| {
+            assert!(panic.location.as_ref().unwrap().contains("lib.rs"));
+            assert!(panic.backtrace.is_some());
+            Response::new()
+                .status(StatusCode::SERVICE_UNAVAILABLE)
+                .body(format!("{} {}: {}", req.method, req.uri.path(), panic.message).into())
+        });
+        let mut server = app.build().serve(|| ());
+
+        for (path, expected) in &[
+            ("/sync", "GET /sync: sync panic"),
+            ("/async", "GET /async: async panic 42"),
+        ] {
+            let req = hyper::Request::get(format!("http://localhost{}", path))
+                .body(Body::empty())
+                .unwrap();
+            let res = server.call(req).wait().unwrap();
+            assert_eq!(res.status(), StatusCode::SERVICE_UNAVAILABLE);
+            let body = res.into_body().concat2().wait().unwrap();
+            assert_eq!(&body[..], expected.as_bytes());
+        }
+    }
+
+    #[test]
+    fn panicking_panic_hook() {
+        use hyper::service::Service;
+
+        let mut app = App::new();
+        app.add(
+            |_req, _res: Response, _state, _next: Next| -> Result<Response, HttpError> {
+                panic!("handler panic")
+            },
+        );
+        app.on_panic(|_panic, _req| panic!("hook panic"));
+        let mut server = app.build().serve(|| ());
+
+        let req = hyper::Request::get("http://localhost/")
+            .body(Body::empty())
+            .unwrap();
+        let res = server.call(req).wait().unwrap();
+        assert_eq!(res.status(), StatusCode::INTERNAL_SERVER_ERROR);
+
+        // panics outside of requests are not recorded
+        let payload = std::panic::catch_unwind(|| panic!("outside")).unwrap_err();
+        let panic = crate::panic::take(payload);
+        assert!(panic.location.is_none() && panic.backtrace.is_none());
+    }
+
+    #[test]
+    fn error_renderer() {
+        use hyper::header::{ACCEPT, CONTENT_TYPE};
+        use hyper::service::Service;
+
+        let mut app = App::new();
+        app.add(move |_req, _res, _state, _next: Next| {
+            Err::<HttpResponse, _>(HttpError::Status(StatusCode::FORBIDDEN))
+        });
+        app.error_renderer(|err, req| {
+            let status = match err {
+                HttpError::Status(status) => status,
+                _ => StatusCode::INTERNAL_SERVER_ERROR,
+            };
+            let json = req.headers.get(ACCEPT).map(|v| v == "application/json");
+            if json == Some(true) {
+                Response::new()
+                    .status(status)
+                    .header(CONTENT_TYPE, "application/json")
+                    .body(format!("{{\"status\":{}}}", status.as_u16()).into())
+            } else {
+                Response::new()
+                    .status(status)
+                    .header(CONTENT_TYPE, "text/html")
+                    .body(format!("<h1>{}</h1>", status).into())
+            }
+        });
+        let mut server = app.build().serve(|| ());
+
+        let req = hyper::Request::get("http://localhost")
+            .header(ACCEPT, "application/json")
+            .body(Body::empty())
+            .unwrap();
+        let res = server.call(req).wait().unwrap();
+        assert_eq!(res.status(), StatusCode::FORBIDDEN);
+        assert_eq!(res.headers()[CONTENT_TYPE], "application/json");
+        let body = res.into_body().concat2().wait().unwrap();
+        assert_eq!(&body[..], b"{\"status\":403}");
+
+        let req = hyper::Request::get("http://localhost")
+            .body(Body::empty())
+            .unwrap();
+        let res = server.call(req).wait().unwrap();
+        assert_eq!(res.headers()[CONTENT_TYPE], "text/html");
+    }
 }
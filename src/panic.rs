@@ -0,0 +1,100 @@
+use std::any::Any;
+use std::backtrace::Backtrace;
+use std::cell::{Cell, RefCell};
+use std::fmt;
+use std::panic;
+use std::sync::Once;
+
+use futures::{Future, Poll};
+
+/// A panic that has been caught while handling a request.
+#[derive(Debug)]
+pub struct Panic {
+    pub message: String,
+    /// The source location the panic originated from, e.g. `src/main.rs:12:5`.
+    pub location: Option<String>,
+    /// Only available if no other panic hook has been installed after the server was started.
+    pub backtrace: Option<Backtrace>,
+}
+
+impl fmt::Display for Panic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.message)?;
+        if let Some(ref location) = self.location {
+            write!(f, " at {}", location)?;
+        }
+        Ok(())
+    }
+}
+
+thread_local! {
+    static LAST_PANIC: RefCell<Option<(Option<String>, Backtrace)>> = const { RefCell::new(None) };
+    static CAPTURING: Cell<bool> = const { Cell::new(false) };
+}
+
+/// Installs a panic hook that remembers the location and backtrace of the last panic of the
+/// current thread, since those are not available anymore once the panic has been caught. Only
+/// panics within futures wrapped with `capture` are recorded, those are reported when the
+/// response is rendered. All other panics are passed on to the previous hook.
+pub(crate) fn install_hook() {
+    static INSTALL: Once = Once::new();
+    INSTALL.call_once(|| {
+        let prev = panic::take_hook();
+        panic::set_hook(Box::new(move |info| {
+            if CAPTURING.with(Cell::get) {
+                let location = info.location().map(|l| l.to_string());
+                let backtrace = Backtrace::force_capture();
+                LAST_PANIC.with(|last| *last.borrow_mut() = Some((location, backtrace)));
+            } else {
+                prev(info);
+            }
+        }));
+    });
+}
+
+/// Records the details of panics that occur while `future` is polled.
+pub(crate) fn capture<F: Future>(future: F) -> Capture<F> {
+    Capture(future)
+}
+
+pub(crate) struct Capture<F>(F);
+
+impl<F: Future> Future for Capture<F> {
+    type Item = F::Item;
+    type Error = F::Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        // restores the previous state even if the future panics
+        struct Restore(bool);
+
+        impl Drop for Restore {
+            fn drop(&mut self) {
+                CAPTURING.with(|capturing| capturing.set(self.0));
+            }
+        }
+
+        let _restore = Restore(CAPTURING.with(|capturing| capturing.replace(true)));
+        self.0.poll()
+    }
+}
+
+/// Collects everything known about the panic that has just been caught on the current thread.
+pub(crate) fn take(payload: Box<dyn Any + Send>) -> Panic {
+    let message = if let Some(s) = payload.downcast_ref::<&str>() {
+        (*s).to_owned()
+    } else if let Some(s) = payload.downcast_ref::<String>() {
+        s.clone()
+    } else {
+        "Box<Any>".to_owned()
+    };
+    let (location, backtrace) = match LAST_PANIC.with(|last| last.borrow_mut().take()) {
+        Some((location, backtrace)) => (location, Some(backtrace)),
+        None => (None, None),
+    };
+
+    Panic {
+        message,
+        location,
+        backtrace,
+    }
+}
@@ -0,0 +1,197 @@
+use std::fmt;
+
+use crate::HttpError;
+use hyper::header::{HeaderValue, CONTENT_TYPE};
+use hyper::{Body, Response, StatusCode};
+use serde::ser::{Serialize, SerializeMap, Serializer};
+use serde_json::{Map, Value};
+
+/// An `application/problem+json` error body as described in RFC 7807.
+#[derive(Debug, Clone)]
+pub struct Problem {
+    status: StatusCode,
+    type_uri: String,
+    title: Option<String>,
+    detail: Option<String>,
+    instance: Option<String>,
+    extensions: Map<String, Value>,
+}
+
+const MEMBERS: &[&str] = &["type", "title", "status", "detail", "instance"];
+
+impl Problem {
+    /// Creates a problem of the type `about:blank`, titled with the canonical reason of `status`.
+    pub fn new(status: StatusCode) -> Self {
+        Problem {
+            status,
+            type_uri: "about:blank".to_owned(),
+            title: status.canonical_reason().map(str::to_owned),
+            detail: None,
+            instance: None,
+            extensions: Map::new(),
+        }
+    }
+
+    pub fn type_uri<T: Into<String>>(mut self, uri: T) -> Self {
+        self.type_uri = uri.into();
+        self
+    }
+
+    pub fn title<T: Into<String>>(mut self, title: T) -> Self {
+        self.title = Some(title.into());
+        self
+    }
+
+    pub fn detail<T: Into<String>>(mut self, detail: T) -> Self {
+        self.detail = Some(detail.into());
+        self
+    }
+
+    pub fn instance<T: Into<String>>(mut self, uri: T) -> Self {
+        self.instance = Some(uri.into());
+        self
+    }
+
+    /// Adds an extension member. Members that would shadow one of the standard members are
+    /// ignored, as are values that fail to serialize.
+    pub fn extension<T: Serialize>(mut self, name: &str, value: T) -> Self {
+        if !MEMBERS.contains(&name) {
+            if let Ok(value) = serde_json::to_value(value) {
+                self.extensions.insert(name.to_owned(), value);
+            }
+        }
+        self
+    }
+
+    pub fn status(&self) -> StatusCode {
+        self.status
+    }
+
+    pub fn into_response(self) -> Result<Response<Body>, http::Error> {
+        let body = serde_json::to_string(&self).unwrap_or_default();
+        Response::builder()
+            .status(self.status)
+            .header(
+                CONTENT_TYPE,
+                HeaderValue::from_static("application/problem+json"),
+            )
+            .body(body.into())
+    }
+}
+
+impl Serialize for Problem {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut map = serializer.serialize_map(None)?;
+        map.serialize_entry("type", &self.type_uri)?;
+        if let Some(ref title) = self.title {
+            map.serialize_entry("title", title)?;
+        }
+        map.serialize_entry("status", &self.status.as_u16())?;
+        if let Some(ref detail) = self.detail {
+            map.serialize_entry("detail", detail)?;
+        }
+        if let Some(ref instance) = self.instance {
+            map.serialize_entry("instance", instance)?;
+        }
+        for (name, value) in &self.extensions {
+            map.serialize_entry(name, value)?;
+        }
+        map.end()
+    }
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.status)?;
+        if let Some(ref detail) = self.detail {
+            write!(f, ": {}", detail)?;
+        }
+        Ok(())
+    }
+}
+
+impl From<Problem> for HttpError {
+    fn from(problem: Problem) -> Self {
+        HttpError::Problem(Box::new(problem))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Problem;
+    use crate::HttpError;
+    use futures::{Future, Stream};
+    use hyper::header::CONTENT_TYPE;
+    use hyper::StatusCode;
+    use serde_json::{json, Value};
+
+    fn render(err: HttpError) -> (StatusCode, String, Value) {
+        let res = err.into_response().unwrap();
+        let status = res.status();
+        let content_type = res.headers()[CONTENT_TYPE].to_str().unwrap().to_owned();
+        let body = res.into_body().concat2().wait().unwrap();
+        (status, content_type, serde_json::from_slice(&body).unwrap())
+    }
+
+    #[test]
+    fn renders_problem_json() {
+        let problem = Problem::new(StatusCode::FORBIDDEN)
+            .type_uri("https://example.com/probs/out-of-credit")
+            .title("You do not have enough credit.")
+            .detail("Your current balance is 30, but that costs 50.")
+            .instance("/account/12345/msgs/abc")
+            .extension("balance", 30)
+            .extension("status", 200);
+
+        assert_eq!(
+            render(problem.into()),
+            (
+                StatusCode::FORBIDDEN,
+                "application/problem+json".to_owned(),
+                json!({
+                    "type": "https://example.com/probs/out-of-credit",
+                    "title": "You do not have enough credit.",
+                    "status": 403,
+                    "detail": "Your current balance is 30, but that costs 50.",
+                    "instance": "/account/12345/msgs/abc",
+                    "balance": 30,
+                })
+            )
+        );
+    }
+
+    #[test]
+    fn ok_with_message() {
+        fn check(id: u32) -> Result<(), HttpError> {
+            crate::ok!(
+                id > 0,
+                StatusCode::UNPROCESSABLE_ENTITY,
+                "invalid id {}",
+                id
+            );
+            Ok(())
+        }
+
+        assert_eq!(
+            render(check(0).unwrap_err()).2,
+            json!({
+                "type": "about:blank",
+                "title": "Unprocessable Entity",
+                "status": 422,
+                "detail": "invalid id 0",
+            })
+        );
+    }
+
+    #[test]
+    fn ok_some_with_message() {
+        fn find(id: Option<u32>) -> Result<u32, HttpError> {
+            Ok(crate::ok_some!(id, StatusCode::NOT_FOUND, "user not found"))
+        }
+
+        assert_eq!(
+            render(find(None).unwrap_err()).2["detail"],
+            "user not found"
+        );
+    }
+}
@@ -0,0 +1,559 @@
+use std::collections::hash_map::RandomState;
+use std::collections::HashMap;
+use std::hash::BuildHasher;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::{
+    ErrorHeaders, HttpError, HttpResponse, Middleware, Next, RemoteAddr, Request, Response,
+    ResponseFuture,
+};
+use futures::future::{self, Either, Future};
+use hyper::header::{HeaderMap, HeaderName, HeaderValue, RETRY_AFTER};
+use hyper::{Body, StatusCode};
+
+/// How many requests a client may send.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Limit {
+    /// Allows bursts of up to `capacity` requests. The bucket is refilled continuously at a rate
+    /// of `capacity` tokens per `per`.
+    TokenBucket { capacity: u64, per: Duration },
+    /// Allows `max` requests within any `window`. Approximated by weighting the count of the
+    /// previous window by how much of it still overlaps with the sliding window.
+    SlidingWindow { max: u64, window: Duration },
+}
+
+/// The outcome of counting a request against a `Limit`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Decision {
+    pub allowed: bool,
+    pub limit: u64,
+    pub remaining: u64,
+    /// Time until the full quota is available again.
+    pub reset: Duration,
+    /// Time until the next request would be allowed, only relevant if it was not.
+    pub retry_after: Duration,
+}
+
+pub type StoreFuture = Box<dyn Future<Item = Decision, Error = HttpError> + Send>;
+
+/// Keeps track of the requests of each client. Implement it to share limits between multiple
+/// instances of an app, e.g. through Redis.
+pub trait Store: Send + Sync {
+    /// Counts a request of the client identified by `key` against `limit`.
+    fn hit(&self, key: &str, limit: &Limit) -> StoreFuture;
+}
+
+impl<T: Store + ?Sized> Store for Arc<T> {
+    fn hit(&self, key: &str, limit: &Limit) -> StoreFuture {
+        (**self).hit(key, limit)
+    }
+}
+
+const SHARDS: usize = 16;
+/// Number of hits to a shard after which expired entries are removed from it.
+const SWEEP_INTERVAL: usize = 1024;
+
+/// The default in-memory store. Its entries are spread over multiple separately locked shards to
+/// reduce contention between concurrent requests.
+pub struct MemoryStore {
+    hasher: RandomState,
+    shards: Vec<Mutex<Shard>>,
+}
+
+#[derive(Default)]
+struct Shard {
+    entries: HashMap<String, Entry>,
+    hits: usize,
+}
+
+struct Entry {
+    state: State,
+    /// When the entry is back to its initial state and can be dropped.
+    expires: Instant,
+}
+
+enum State {
+    Bucket {
+        tokens: f64,
+        updated: Instant,
+    },
+    Window {
+        start: Instant,
+        current: u64,
+        previous: u64,
+    },
+}
+
+impl MemoryStore {
+    pub fn new() -> Self {
+        MemoryStore {
+            hasher: RandomState::new(),
+            shards: (0..SHARDS).map(|_| Mutex::new(Shard::default())).collect(),
+        }
+    }
+
+    fn hit_at(&self, key: &str, limit: &Limit, now: Instant) -> Decision {
+        let mut shard = self.shards[self.hasher.hash_one(key) as usize % SHARDS]
+            .lock()
+            .unwrap();
+
+        shard.hits += 1;
+        if shard.hits >= SWEEP_INTERVAL {
+            shard.hits = 0;
+            shard.entries.retain(|_, entry| entry.expires > now);
+        }
+
+        if let Some(entry) = shard.entries.get_mut(key) {
+            return entry.hit(limit, now);
+        }
+        let mut entry = Entry::new(limit, now);
+        let decision = entry.hit(limit, now);
+        shard.entries.insert(key.to_owned(), entry);
+        decision
+    }
+}
+
+impl Default for MemoryStore {
+    fn default() -> Self {
+        MemoryStore::new()
+    }
+}
+
+impl Store for MemoryStore {
+    fn hit(&self, key: &str, limit: &Limit) -> StoreFuture {
+        Box::new(future::ok(self.hit_at(key, limit, Instant::now())))
+    }
+}
+
+impl Entry {
+    fn new(limit: &Limit, now: Instant) -> Self {
+        let state = match *limit {
+            Limit::TokenBucket { capacity, .. } => State::Bucket {
+                tokens: capacity as f64,
+                updated: now,
+            },
+            Limit::SlidingWindow { .. } => State::Window {
+                start: now,
+                current: 0,
+                previous: 0,
+            },
+        };
+        Entry {
+            state,
+            expires: now,
+        }
+    }
+
+    fn hit(&mut self, limit: &Limit, now: Instant) -> Decision {
+        match (*limit, &mut self.state) {
+            (
+                Limit::TokenBucket { capacity, per },
+                &mut State::Bucket {
+                    ref mut tokens,
+                    ref mut updated,
+                },
+            ) => {
+                let rate = capacity as f64 / secs(per);
+                let elapsed = secs(now.saturating_duration_since(*updated));
+                *tokens = (*tokens + elapsed * rate).min(capacity as f64);
+                *updated = now;
+
+                let allowed = *tokens >= 1.0;
+                if allowed {
+                    *tokens -= 1.0;
+                }
+                let reset = duration((capacity as f64 - *tokens) / rate);
+                self.expires = now + reset;
+                Decision {
+                    allowed,
+                    limit: capacity,
+                    remaining: *tokens as u64,
+                    reset,
+                    retry_after: duration((1.0 - *tokens).max(0.0) / rate),
+                }
+            }
+            (
+                Limit::SlidingWindow { max, window },
+                &mut State::Window {
+                    ref mut start,
+                    ref mut current,
+                    ref mut previous,
+                },
+            ) => {
+                let mut elapsed = now.saturating_duration_since(*start);
+                if elapsed >= window * 2 {
+                    *start = now;
+                    *current = 0;
+                    *previous = 0;
+                    elapsed = Duration::from_secs(0);
+                } else if elapsed >= window {
+                    *start += window;
+                    *previous = *current;
+                    *current = 0;
+                    elapsed -= window;
+                }
+
+                let overlap = 1.0 - secs(elapsed) / secs(window);
+                let count = *previous as f64 * overlap + *current as f64;
+                let allowed = count + 1.0 <= max as f64;
+                if allowed {
+                    *current += 1;
+                }
+                let used = (*previous as f64 * overlap).ceil() as u64 + *current;
+
+                let reset = window - elapsed;
+                // the weighted count of the previous window drops below the limit before the
+                // current window ends, if the current window alone is not already at the limit
+                let retry_after = if *current < max && *previous > 0 {
+                    let needed = 1.0 - (max - 1 - *current) as f64 / *previous as f64;
+                    duration((secs(window) * needed - secs(elapsed)).max(0.0))
+                } else {
+                    reset
+                };
+                self.expires = *start + window * 2;
+                Decision {
+                    allowed,
+                    limit: max,
+                    remaining: max.saturating_sub(used),
+                    reset,
+                    retry_after,
+                }
+            }
+            // the limit of a key changed, e.g. because two limiters share a store
+            _ => {
+                *self = Entry::new(limit, now);
+                self.hit(limit, now)
+            }
+        }
+    }
+}
+
+fn secs(duration: Duration) -> f64 {
+    duration.as_secs() as f64 + f64::from(duration.subsec_nanos()) / 1e9
+}
+
+fn duration(secs: f64) -> Duration {
+    Duration::from_nanos((secs * 1e9).ceil() as u64)
+}
+
+type Key<S> = dyn Fn(&Request, &S) -> Option<String> + Send + Sync;
+
+pub struct RateLimit<S> {
+    limit: Limit,
+    /// Identifies clients by their address if not set.
+    key: Option<Arc<Key<S>>>,
+    store: Arc<dyn Store>,
+}
+
+impl<S> Clone for RateLimit<S> {
+    fn clone(&self) -> Self {
+        RateLimit {
+            limit: self.limit,
+            key: self.key.clone(),
+            store: self.store.clone(),
+        }
+    }
+}
+
+/// Limits the requests per client, which is identified by its IP address unless configured
+/// otherwise. Throttled requests are answered with `429 Too Many Requests` and a `Retry-After`
+/// header. All responses get the `RateLimit-Limit`, `RateLimit-Remaining` and `RateLimit-Reset`
+/// headers.
+///
+/// The IP address is only known if it has been provided with `Serve::remote_addr`, requests
+/// without one are answered with `500 Internal Server Error` unless clients are identified with
+/// `by_header` or `by_key` instead.
+pub fn rate_limit<S>(limit: Limit) -> RateLimit<S> {
+    RateLimit {
+        limit,
+        key: None,
+        store: Arc::new(MemoryStore::new()),
+    }
+}
+
+impl<S> RateLimit<S> {
+    /// Identifies clients by the value of a header, e.g. an API key. Requests without the header
+    /// are not limited.
+    pub fn by_header(mut self, name: HeaderName) -> Self {
+        self.key = Some(Arc::new(move |req: &Request, _: &S| {
+            req.headers()
+                .get(&name)
+                .map(|value| String::from_utf8_lossy(value.as_bytes()).into_owned())
+        }));
+        self
+    }
+
+    /// Identifies clients by the key returned from `key`, e.g. a user ID. Requests `key` returns
+    /// `None` for are not limited.
+    pub fn by_key<F>(mut self, key: F) -> Self
+    where
+        F: Fn(&Request, &S) -> Option<String> + Send + Sync + 'static,
+    {
+        self.key = Some(Arc::new(key));
+        self
+    }
+
+    /// Keeps track of the requests in `store` instead of in memory. Pass an `Arc` to share a
+    /// store between multiple limiters, in which case their keys must not overlap.
+    pub fn store<T>(mut self, store: T) -> Self
+    where
+        T: Store + 'static,
+    {
+        self.store = Arc::new(store);
+        self
+    }
+}
+
+impl<S> Middleware<S> for RateLimit<S>
+where
+    S: Send + 'static,
+{
+    fn handle(&self, req: Request, res: Response, state: S, next: Next<S>) -> ResponseFuture {
+        let key = match self.key {
+            Some(ref key) => match key(&req, &state) {
+                Some(key) => key,
+                None => return next(req, res, state),
+            },
+            None => match req.extensions().get::<RemoteAddr>() {
+                Some(addr) => addr.0.ip().to_string(),
+                None => {
+                    return Box::new(future::err(HttpError::internal(
+                        "rate_limit requires the client address, provide it with \
+                         `Serve::remote_addr` or identify clients with `by_key`",
+                    )))
+                }
+            },
+        };
+
+        let errors = req.extensions().get::<ErrorHeaders>().cloned();
+        Box::new(self.store.hit(&key, &self.limit).and_then(move |decision| {
+            if !decision.allowed {
+                return Either::A(future::err(HttpError::Response(too_many_requests(
+                    &decision,
+                ))));
+            }
+            Either::B(next(req, res, state).then(move |result| {
+                ErrorHeaders::add(errors.as_ref(), result, |map| headers(map, &decision))
+            }))
+        }))
+    }
+}
+
+fn headers(headers: &mut HeaderMap, decision: &Decision) {
+    headers.insert(
+        HeaderName::from_static("ratelimit-limit"),
+        HeaderValue::from(decision.limit),
+    );
+    headers.insert(
+        HeaderName::from_static("ratelimit-remaining"),
+        HeaderValue::from(decision.remaining),
+    );
+    headers.insert(
+        HeaderName::from_static("ratelimit-reset"),
+        HeaderValue::from(ceil_secs(decision.reset)),
+    );
+}
+
+fn too_many_requests(decision: &Decision) -> HttpResponse {
+    let status = StatusCode::TOO_MANY_REQUESTS;
+    let mut res = HttpResponse::new(Body::from(status.canonical_reason().unwrap_or_default()));
+    *res.status_mut() = status;
+    headers(res.headers_mut(), decision);
+    res.headers_mut().insert(
+        RETRY_AFTER,
+        HeaderValue::from(ceil_secs(decision.retry_after).max(1)),
+    );
+    res
+}
+
+fn ceil_secs(duration: Duration) -> u64 {
+    duration.as_secs() + if duration.subsec_nanos() > 0 { 1 } else { 0 }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Limit, MemoryStore};
+    use crate::{
+        default_fallback, rate_limit, App, HttpError, HttpResponse, Next, RemoteAddr, Request,
+        Response,
+    };
+    use futures::Future;
+    use hyper::header::RETRY_AFTER;
+    use hyper::{Body, StatusCode};
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn token_bucket() {
+        let store = MemoryStore::new();
+        let limit = Limit::TokenBucket {
+            capacity: 2,
+            per: Duration::from_secs(10),
+        };
+        let now = Instant::now();
+
+        let first = store.hit_at("a", &limit, now);
+        assert!(first.allowed);
+        assert_eq!(first.remaining, 1);
+        assert!(store.hit_at("a", &limit, now).allowed);
+
+        let denied = store.hit_at("a", &limit, now);
+        assert!(!denied.allowed);
+        assert_eq!(denied.remaining, 0);
+        assert_eq!(denied.retry_after, Duration::from_secs(5));
+        assert_eq!(denied.reset, Duration::from_secs(10));
+
+        // other keys have their own bucket
+        assert!(store.hit_at("b", &limit, now).allowed);
+
+        // one token is refilled every 5 seconds
+        assert!(
+            store
+                .hit_at("a", &limit, now + Duration::from_secs(5))
+                .allowed
+        );
+        assert!(
+            !store
+                .hit_at("a", &limit, now + Duration::from_secs(5))
+                .allowed
+        );
+    }
+
+    #[test]
+    fn sliding_window() {
+        let store = MemoryStore::new();
+        let limit = Limit::SlidingWindow {
+            max: 4,
+            window: Duration::from_secs(10),
+        };
+        let now = Instant::now();
+
+        for remaining in (0..4).rev() {
+            let decision = store.hit_at("a", &limit, now);
+            assert!(decision.allowed);
+            assert_eq!(decision.remaining, remaining);
+        }
+        let denied = store.hit_at("a", &limit, now + Duration::from_secs(5));
+        assert!(!denied.allowed);
+        assert_eq!(denied.reset, Duration::from_secs(5));
+
+        // halfway through the next window, half of the previous requests still count
+        let later = now + Duration::from_secs(15);
+        assert!(store.hit_at("a", &limit, later).allowed);
+        assert!(store.hit_at("a", &limit, later).allowed);
+        let denied = store.hit_at("a", &limit, later);
+        assert!(!denied.allowed);
+        assert_eq!(denied.retry_after, Duration::from_millis(2500));
+
+        // two windows later everything is forgotten
+        let decision = store.hit_at("a", &limit, now + Duration::from_secs(30));
+        assert_eq!(decision.remaining, 3);
+    }
+
+    fn call(
+        app: &App<u32>,
+        addr: Option<&str>,
+        state: u32,
+    ) -> Result<hyper::Response<Body>, HttpError> {
+        let mut req = hyper::Request::get("/").body(Body::empty()).unwrap();
+        if let Some(addr) = addr {
+            req.extensions_mut()
+                .insert(RemoteAddr(addr.parse().unwrap()));
+        }
+        app.execute(req, Response::new(), state, default_fallback)
+            .wait()
+    }
+
+    #[test]
+    fn throttles_requests() {
+        let mut app = App::new();
+        app.add(rate_limit(Limit::TokenBucket {
+            capacity: 1,
+            per: Duration::from_secs(60),
+        }));
+        app.add(
+            |_req: Request, mut res: Response, _state, _next: Next<u32>| res.body(Body::empty()),
+        );
+        let app = app.build();
+
+        let res = call(&app, Some("10.0.0.1:1234"), 0).unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(res.headers()["ratelimit-limit"], "1");
+        assert_eq!(res.headers()["ratelimit-remaining"], "0");
+        assert_eq!(res.headers()["ratelimit-reset"], "60");
+
+        match call(&app, Some("10.0.0.1:4321"), 0) {
+            Err(HttpError::Response(res)) => {
+                assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+                assert_eq!(res.headers()[RETRY_AFTER], "60");
+                assert_eq!(res.headers()["ratelimit-remaining"], "0");
+            }
+            _ => panic!("expected too many requests"),
+        }
+
+        assert!(call(&app, Some("10.0.0.2:1234"), 0).is_ok());
+        // the address is required
+        match call(&app, None, 0) {
+            Err(err) => assert_eq!(err.status(), StatusCode::INTERNAL_SERVER_ERROR),
+            Ok(_) => panic!("expected an internal server error"),
+        }
+    }
+
+    #[test]
+    fn adds_headers_to_errors() {
+        use hyper::service::Service;
+
+        let mut app = App::new();
+        app.add(rate_limit(Limit::TokenBucket {
+            capacity: 5,
+            per: Duration::from_secs(60),
+        }));
+        app.add(|req: Request, _res, _state, _next: Next<()>| {
+            if req.uri().path() == "/response" {
+                let res = hyper::Response::builder()
+                    .status(StatusCode::CONFLICT)
+                    .body(Body::empty())
+                    .unwrap();
+                Err::<HttpResponse, _>(HttpError::Response(res))
+            } else {
+                Err(HttpError::Status(StatusCode::NOT_FOUND))
+            }
+        });
+        let mut server = app
+            .build()
+            .serve(|| ())
+            .remote_addr("10.0.0.1:1234".parse().unwrap());
+
+        for (path, status) in &[
+            ("/response", StatusCode::CONFLICT),
+            ("/status", StatusCode::NOT_FOUND),
+        ] {
+            let req = hyper::Request::get(format!("http://localhost{}", path))
+                .body(Body::empty())
+                .unwrap();
+            let res = server.call(req).wait().unwrap();
+            assert_eq!(res.status(), *status);
+            assert_eq!(res.headers()["ratelimit-limit"], "5");
+        }
+    }
+
+    #[test]
+    fn custom_keys() {
+        let mut app = App::new();
+        app.add(
+            rate_limit(Limit::SlidingWindow {
+                max: 1,
+                window: Duration::from_secs(60),
+            })
+            .by_key(|_req: &Request, user: &u32| Some(user.to_string())),
+        );
+        app.add(
+            |_req: Request, mut res: Response, _state, _next: Next<u32>| res.body(Body::empty()),
+        );
+        let app = app.build();
+
+        assert!(call(&app, None, 1).is_ok());
+        assert!(call(&app, None, 1).is_err());
+        assert!(call(&app, None, 2).is_ok());
+    }
+}
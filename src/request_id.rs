@@ -0,0 +1,207 @@
+use std::collections::hash_map::RandomState;
+use std::fmt;
+use std::hash::{BuildHasher, Hasher};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+use crate::{HttpResponse, Middleware, Next, Request, Response, ResponseFuture};
+use futures::Future;
+use hyper::header::{HeaderName, HeaderValue};
+
+/// The ID of the current request, available through the request extensions and, for the error and
+/// panic hooks, through the extensions of the request head.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RequestId(pub String);
+
+impl fmt::Display for RequestId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+#[derive(Clone)]
+pub struct RequestIds {
+    header: HeaderName,
+    generator: Arc<dyn Fn() -> String + Send + Sync>,
+}
+
+/// Takes the ID of each request from its `X-Request-Id` header, or generates a random one if it
+/// is missing or invalid, and sends it back with the response.
+pub fn request_id() -> RequestIds {
+    RequestIds {
+        header: HeaderName::from_static("x-request-id"),
+        generator: Arc::new(generate),
+    }
+}
+
+impl RequestIds {
+    /// The header the ID is read from and sent back in (defaults to `X-Request-Id`).
+    pub fn header(mut self, name: HeaderName) -> Self {
+        self.header = name;
+        self
+    }
+
+    /// Generates IDs for requests without one, instead of a random 128 bit hex string.
+    pub fn generator<F>(mut self, generator: F) -> Self
+    where
+        F: Fn() -> String + Send + Sync + 'static,
+    {
+        self.generator = Arc::new(generator);
+        self
+    }
+}
+
+impl<S> Middleware<S> for RequestIds
+where
+    S: 'static,
+{
+    fn handle(&self, mut req: Request, res: Response, state: S, next: Next<S>) -> ResponseFuture {
+        let value = match req.headers().get(&self.header) {
+            Some(value) if valid(value) => value.clone(),
+            _ => match HeaderValue::from_str(&(self.generator)()) {
+                Ok(value) => value,
+                Err(_) => HeaderValue::from_str(&generate()).unwrap(),
+            },
+        };
+        // only visible ASCII is valid, so the conversion cannot fail
+        let id = RequestId(value.to_str().unwrap_or_default().to_owned());
+
+        // propagate a generated ID to middlewares that forward the request
+        req.headers_mut().insert(self.header.clone(), value.clone());
+        if let Some(slot) = req.extensions().get::<Arc<Slot>>() {
+            *slot.0.lock().unwrap() = Some((self.header.clone(), id.clone()));
+        }
+        req.extensions_mut().insert(id);
+
+        let header = self.header.clone();
+        Box::new(next(req, res, state).map(move |mut res| {
+            res.headers_mut().insert(header, value);
+            res
+        }))
+    }
+}
+
+fn valid(value: &HeaderValue) -> bool {
+    let value = value.as_bytes();
+    !value.is_empty() && value.len() <= 200 && value.iter().all(|b| b.is_ascii_graphic())
+}
+
+/// A random 128 bit hex string. The random keys of `RandomState` together with a counter keep
+/// the IDs unique without an additional dependency.
+fn generate() -> String {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+    let count = COUNTER.fetch_add(1, Ordering::Relaxed);
+
+    let mut id = String::with_capacity(32);
+    for _ in 0..2 {
+        let mut hasher = RandomState::new().build_hasher();
+        hasher.write_u64(count);
+        id.push_str(&format!("{:016x}", hasher.finish()));
+    }
+    id
+}
+
+/// Put into the extensions of every request by `Serve`, so that the ID is known when the request
+/// ended in an error or a panic.
+#[derive(Default)]
+pub(crate) struct Slot(Mutex<Option<(HeaderName, RequestId)>>);
+
+impl Slot {
+    pub(crate) fn take(&self) -> Option<(HeaderName, RequestId)> {
+        self.0.lock().unwrap().take()
+    }
+}
+
+/// Adds the request ID to a response rendered by `Serve`.
+pub(crate) fn echo(mut res: HttpResponse, id: Option<(HeaderName, RequestId)>) -> HttpResponse {
+    if let Some((header, RequestId(id))) = id {
+        if let Ok(value) = HeaderValue::from_str(&id) {
+            res.headers_mut().insert(header, value);
+        }
+    }
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{generate, RequestId};
+    use crate::{request_id, App, HttpError, Next, Request, Response};
+    use futures::Future;
+    use hyper::header::HeaderName;
+    use hyper::service::Service;
+    use hyper::{Body, StatusCode};
+    use std::sync::{Arc, Mutex};
+
+    #[test]
+    fn generates_unique_ids() {
+        let a = generate();
+        let b = generate();
+        assert_eq!(a.len(), 32);
+        assert_ne!(a, b);
+    }
+
+    #[test]
+    fn propagates_ids() {
+        let mut app = App::new();
+        app.add(request_id().header(HeaderName::from_static("x-correlation-id")));
+        app.add(|req: Request, mut res: Response, _state, _next: Next<()>| {
+            let id = req.extensions().get::<RequestId>().unwrap();
+            assert_eq!(req.headers()["x-correlation-id"], id.0.as_str());
+            res.body(Body::empty())
+        });
+        let mut server = app.build().serve(|| ());
+
+        let req = hyper::Request::get("/")
+            .header("x-correlation-id", "abc-123")
+            .body(Body::empty())
+            .unwrap();
+        let res = server.call(req).wait().unwrap();
+        assert_eq!(res.headers()["x-correlation-id"], "abc-123");
+
+        let req = hyper::Request::get("/")
+            .header("x-correlation-id", "")
+            .body(Body::empty())
+            .unwrap();
+        let res = server.call(req).wait().unwrap();
+        assert_eq!(res.headers()["x-correlation-id"].len(), 32);
+    }
+
+    #[test]
+    fn available_to_hooks() {
+        let reported = Arc::new(Mutex::new(Vec::new()));
+        let mut app = App::new();
+        app.add(request_id().generator(|| "generated".to_owned()));
+        app.add(|req: Request, _res: Response, _state, _next: Next<()>| {
+            if req.uri().path() == "/panic" {
+                panic!("boom");
+            }
+            Err::<Response, _>(HttpError::Status(StatusCode::INTERNAL_SERVER_ERROR))
+        });
+        {
+            let reported = reported.clone();
+            app.on_error(move |_err, req| {
+                let id = req.extensions.get::<RequestId>().unwrap();
+                reported.lock().unwrap().push(id.to_string());
+            });
+        }
+        {
+            let reported = reported.clone();
+            app.on_panic(move |_panic, req| {
+                let id = req.extensions.get::<RequestId>().unwrap();
+                reported.lock().unwrap().push(id.to_string());
+                hyper::Response::builder()
+                    .status(StatusCode::INTERNAL_SERVER_ERROR)
+                    .body(Body::empty())
+            });
+        }
+        let mut server = app.build().serve(|| ());
+
+        for path in &["/error", "/panic"] {
+            let req = hyper::Request::get(*path).body(Body::empty()).unwrap();
+            let res = server.call(req).wait().unwrap();
+            assert_eq!(res.status(), StatusCode::INTERNAL_SERVER_ERROR);
+            assert_eq!(res.headers()["x-request-id"], "generated");
+        }
+        assert_eq!(*reported.lock().unwrap(), vec!["generated", "generated"]);
+    }
+}
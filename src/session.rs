@@ -0,0 +1,925 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::mem;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::{HttpError, HttpResponse, Middleware, Next, Request, Response, ResponseFuture};
+pub use cookie::SameSite;
+use cookie::{Cookie, CookieJar, Key};
+use futures::future::{self, Future};
+use hyper::header::{HeaderValue, COOKIE, SET_COOKIE};
+use rand::rngs::OsRng;
+use rand::RngCore;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use serde_json::{Map, Value};
+
+/// The data of a session together with when it was created and last accessed.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Record {
+    pub data: Map<String, Value>,
+    pub created: SystemTime,
+    pub accessed: SystemTime,
+}
+
+impl Record {
+    pub fn to_json(&self) -> String {
+        self.to_value().to_string()
+    }
+
+    pub fn from_json(json: &str) -> Option<Self> {
+        Record::from_value(&serde_json::from_str(json).ok()?)
+    }
+
+    fn to_value(&self) -> Value {
+        serde_json::json!({
+            "created": secs(self.created),
+            "accessed": secs(self.accessed),
+            "data": self.data,
+        })
+    }
+
+    fn from_value(value: &Value) -> Option<Self> {
+        Some(Record {
+            data: value.get("data")?.as_object()?.clone(),
+            created: UNIX_EPOCH + Duration::from_secs(value.get("created")?.as_u64()?),
+            accessed: UNIX_EPOCH + Duration::from_secs(value.get("accessed")?.as_u64()?),
+        })
+    }
+}
+
+fn secs(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+fn elapsed(since: SystemTime, now: SystemTime) -> Duration {
+    now.duration_since(since).unwrap_or_default()
+}
+
+pub type SessionFuture<T> = Box<dyn Future<Item = T, Error = HttpError> + Send>;
+
+/// Where sessions are kept. The session cookie holds a key that is only meaningful to the store,
+/// e.g. a session ID or the encrypted session itself.
+pub trait SessionStore: Send + Sync {
+    /// Loads the session `key` refers to, if it exists and has not expired.
+    fn load(&self, key: &str) -> SessionFuture<Option<Record>>;
+
+    /// Saves `record`, replacing the session `key` refers to if given, and returns the key for
+    /// the cookie. The session may be dropped after `ttl`.
+    fn save(
+        &self,
+        key: Option<&str>,
+        record: &Record,
+        ttl: Option<Duration>,
+    ) -> SessionFuture<String>;
+
+    fn destroy(&self, key: &str) -> SessionFuture<()>;
+}
+
+impl<T: SessionStore + ?Sized> SessionStore for Arc<T> {
+    fn load(&self, key: &str) -> SessionFuture<Option<Record>> {
+        (**self).load(key)
+    }
+
+    fn save(
+        &self,
+        key: Option<&str>,
+        record: &Record,
+        ttl: Option<Duration>,
+    ) -> SessionFuture<String> {
+        (**self).save(key, record, ttl)
+    }
+
+    fn destroy(&self, key: &str) -> SessionFuture<()> {
+        (**self).destroy(key)
+    }
+}
+
+/// A random 256 bit hex string from the operating system's secure random number generator.
+pub(crate) fn generate_id() -> String {
+    let mut bytes = [0u8; 32];
+    OsRng.fill_bytes(&mut bytes);
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Only IDs as generated by `generate_id` are looked up, which e.g. prevents path traversal.
+fn valid_id(id: &str) -> bool {
+    id.len() == 64 && id.bytes().all(|b| b.is_ascii_hexdigit())
+}
+
+/// Number of saves after which expired sessions are removed from a `MemorySessionStore` or
+/// `FileSessionStore`.
+const SWEEP_INTERVAL: usize = 1024;
+
+/// Keeps sessions in memory, so they are lost on restart and not shared between processes.
+#[derive(Default)]
+pub struct MemorySessionStore {
+    state: Mutex<MemoryState>,
+}
+
+#[derive(Default)]
+struct MemoryState {
+    sessions: HashMap<String, (Record, Option<SystemTime>)>,
+    saves: usize,
+}
+
+impl MemorySessionStore {
+    pub fn new() -> Self {
+        MemorySessionStore::default()
+    }
+}
+
+impl SessionStore for MemorySessionStore {
+    fn load(&self, key: &str) -> SessionFuture<Option<Record>> {
+        let now = SystemTime::now();
+        let mut state = self.state.lock().unwrap();
+        let record = match state.sessions.get(key) {
+            Some(&(_, Some(expires))) if expires <= now => {
+                state.sessions.remove(key);
+                None
+            }
+            Some((record, _)) => Some(record.clone()),
+            None => None,
+        };
+        Box::new(future::ok(record))
+    }
+
+    fn save(
+        &self,
+        key: Option<&str>,
+        record: &Record,
+        ttl: Option<Duration>,
+    ) -> SessionFuture<String> {
+        let now = SystemTime::now();
+        let mut state = self.state.lock().unwrap();
+
+        state.saves += 1;
+        if state.saves >= SWEEP_INTERVAL {
+            state.saves = 0;
+            state
+                .sessions
+                .retain(|_, &mut (_, expires)| expires.is_none_or(|expires| expires > now));
+        }
+
+        let id = key.map(str::to_owned).unwrap_or_else(generate_id);
+        state
+            .sessions
+            .insert(id.clone(), (record.clone(), ttl.map(|ttl| now + ttl)));
+        Box::new(future::ok(id))
+    }
+
+    fn destroy(&self, key: &str) -> SessionFuture<()> {
+        self.state.lock().unwrap().sessions.remove(key);
+        Box::new(future::ok(()))
+    }
+}
+
+/// Keeps each session in a JSON file named after its ID within a directory. Expired sessions are
+/// removed when they are loaded the next time, and regularly by a background thread.
+pub struct FileSessionStore {
+    dir: PathBuf,
+    saves: AtomicUsize,
+    sweeping: Arc<AtomicBool>,
+}
+
+impl FileSessionStore {
+    /// The directory is created when the first session is saved.
+    pub fn new<P: Into<PathBuf>>(dir: P) -> Self {
+        FileSessionStore {
+            dir: dir.into(),
+            saves: AtomicUsize::new(0),
+            sweeping: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    fn load_file(&self, id: &str) -> io::Result<Option<Record>> {
+        load_file(&self.dir, id)
+    }
+
+    /// Writes to a temporary file first and renames it, so that concurrent loads never see a
+    /// partially written session.
+    fn save_file(&self, id: &str, record: &Record, ttl: Option<Duration>) -> io::Result<()> {
+        let expires = ttl.map(|ttl| secs(SystemTime::now() + ttl));
+        let value = serde_json::json!({ "expires": expires, "record": record.to_value() });
+        fs::create_dir_all(&self.dir)?;
+        let tmp = self.dir.join(format!(".{}.tmp", generate_id()));
+        if let Err(err) = fs::write(&tmp, value.to_string()) {
+            let _ = fs::remove_file(&tmp);
+            return Err(err);
+        }
+        fs::rename(&tmp, self.dir.join(id)).inspect_err(|_| {
+            let _ = fs::remove_file(&tmp);
+        })
+    }
+
+    /// Removes expired sessions on another thread, unless that is still busy with the last sweep.
+    fn start_sweep(&self) {
+        if self.sweeping.swap(true, Ordering::AcqRel) {
+            return;
+        }
+        let dir = self.dir.clone();
+        let sweeping = self.sweeping.clone();
+        thread::spawn(move || {
+            if let Err(err) = sweep(&dir) {
+                log::warn!("Failed to remove expired sessions: {}", err);
+            }
+            sweeping.store(false, Ordering::Release);
+        });
+    }
+}
+
+/// Files that cannot be parsed are reported as errors rather than treated as missing, since that
+/// would replace the session and log the user out.
+fn load_file(dir: &Path, id: &str) -> io::Result<Option<Record>> {
+    if !valid_id(id) {
+        return Ok(None);
+    }
+    let path = dir.join(id);
+    let contents = match fs::read_to_string(&path) {
+        Ok(contents) => contents,
+        Err(ref err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(err) => return Err(err),
+    };
+    let value: Value = serde_json::from_str(&contents)
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+    if let Some(expires) = value.get("expires").and_then(Value::as_u64) {
+        if expires <= secs(SystemTime::now()) {
+            // another request or the sweep may have been faster
+            match fs::remove_file(&path) {
+                Err(ref err) if err.kind() == io::ErrorKind::NotFound => {}
+                result => result?,
+            }
+            return Ok(None);
+        }
+    }
+    match value.get("record").and_then(Record::from_value) {
+        Some(record) => Ok(Some(record)),
+        None => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("invalid session file {}", path.display()),
+        )),
+    }
+}
+
+/// Removes the files of all expired sessions in `dir`.
+fn sweep(dir: &Path) -> io::Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let name = entry?.file_name();
+        if let Some(id) = name.to_str().filter(|name| valid_id(name)) {
+            // unreadable files are reported when their session is loaded
+            let _ = load_file(dir, id);
+        }
+    }
+    Ok(())
+}
+
+impl SessionStore for FileSessionStore {
+    fn load(&self, key: &str) -> SessionFuture<Option<Record>> {
+        Box::new(future::result(
+            self.load_file(key).map_err(HttpError::internal),
+        ))
+    }
+
+    fn save(
+        &self,
+        key: Option<&str>,
+        record: &Record,
+        ttl: Option<Duration>,
+    ) -> SessionFuture<String> {
+        let id = match key {
+            Some(key) if valid_id(key) => key.to_owned(),
+            _ => generate_id(),
+        };
+        if self.saves.fetch_add(1, Ordering::Relaxed) + 1 >= SWEEP_INTERVAL {
+            self.saves.store(0, Ordering::Relaxed);
+            self.start_sweep();
+        }
+        Box::new(future::result(
+            self.save_file(&id, record, ttl)
+                .map(|_| id)
+                .map_err(HttpError::internal),
+        ))
+    }
+
+    fn destroy(&self, key: &str) -> SessionFuture<()> {
+        let result = if valid_id(key) {
+            match fs::remove_file(self.dir.join(key)) {
+                Err(ref err) if err.kind() == io::ErrorKind::NotFound => Ok(()),
+                result => result,
+            }
+        } else {
+            Ok(())
+        };
+        Box::new(future::result(result.map_err(HttpError::internal)))
+    }
+}
+
+/// Name the encrypted value is bound to, independent of the name of the session cookie.
+const COOKIE_STORE_NAME: &str = "session";
+
+/// Keeps the whole session in the cookie, encrypted and authenticated with a key derived from a
+/// secret. Nothing is stored on the server, which also means that sessions cannot be revoked
+/// before they time out and have to fit into a cookie (about 4 KB).
+pub struct CookieSessionStore {
+    key: Key,
+}
+
+impl CookieSessionStore {
+    /// Panics if `secret` is shorter than 32 bytes.
+    pub fn new(secret: &[u8]) -> Self {
+        CookieSessionStore {
+            key: Key::derive_from(secret),
+        }
+    }
+}
+
+impl SessionStore for CookieSessionStore {
+    fn load(&self, key: &str) -> SessionFuture<Option<Record>> {
+        let record = CookieJar::new()
+            .private(&self.key)
+            .decrypt(Cookie::new(COOKIE_STORE_NAME, key.to_owned()))
+            .and_then(|cookie| Record::from_json(cookie.value()));
+        Box::new(future::ok(record))
+    }
+
+    fn save(
+        &self,
+        _key: Option<&str>,
+        record: &Record,
+        _ttl: Option<Duration>,
+    ) -> SessionFuture<String> {
+        let mut jar = CookieJar::new();
+        jar.private_mut(&self.key)
+            .add(Cookie::new(COOKIE_STORE_NAME, record.to_json()));
+        let value = jar
+            .get(COOKIE_STORE_NAME)
+            .map(|cookie| cookie.value().to_owned())
+            .unwrap_or_default();
+        if value.len() > 4000 {
+            return Box::new(future::err(HttpError::internal(
+                "session too large to be stored in a cookie",
+            )));
+        }
+        Box::new(future::ok(value))
+    }
+
+    fn destroy(&self, _key: &str) -> SessionFuture<()> {
+        Box::new(future::ok(()))
+    }
+}
+
+/// The session of the current request, available through the request extensions.
+#[derive(Clone)]
+pub struct Session(Arc<Mutex<SessionState>>);
+
+type RegenerateHook = dyn Fn(&Session) + Send + Sync;
+
+#[derive(Default)]
+struct SessionState {
+    data: Map<String, Value>,
+    changed: bool,
+    regenerate: bool,
+    destroyed: bool,
+    on_regenerate: Vec<Arc<RegenerateHook>>,
+}
+
+impl Session {
+    fn new(data: Map<String, Value>) -> Self {
+        Session(Arc::new(Mutex::new(SessionState {
+            data,
+            ..SessionState::default()
+        })))
+    }
+
+    /// The value of `key`, or `None` if it is missing or cannot be deserialized into `T`.
+    pub fn get<T: DeserializeOwned>(&self, key: &str) -> Option<T> {
+        let value = self.0.lock().unwrap().data.get(key).cloned()?;
+        serde_json::from_value(value).ok()
+    }
+
+    pub fn set<T: Serialize>(&self, key: &str, value: T) -> Result<(), HttpError> {
+        let value = serde_json::to_value(value).map_err(HttpError::internal)?;
+        let mut state = self.0.lock().unwrap();
+        if state.data.get(key) != Some(&value) {
+            state.data.insert(key.to_owned(), value);
+            state.changed = true;
+        }
+        Ok(())
+    }
+
+    pub fn remove(&self, key: &str) {
+        let mut state = self.0.lock().unwrap();
+        if state.data.remove(key).is_some() {
+            state.changed = true;
+        }
+    }
+
+    /// Moves the session to a new ID, which should be done whenever the privileges of a session
+    /// change, e.g. on login, to prevent session fixation.
+    pub fn regenerate(&self) {
+        let hooks = {
+            let mut state = self.0.lock().unwrap();
+            state.regenerate = true;
+            state.on_regenerate.clone()
+        };
+        for hook in hooks {
+            hook(self);
+        }
+    }
+
+    /// Calls `hook` if the session is regenerated while handling the current request, e.g. to
+    /// remove data that must not survive a change of privileges.
+    pub fn on_regenerate<F>(&self, hook: F)
+    where
+        F: Fn(&Session) + Send + Sync + 'static,
+    {
+        self.0.lock().unwrap().on_regenerate.push(Arc::new(hook));
+    }
+
+    /// Removes all data and ends the session, e.g. on logout. Data set afterwards is stored in a
+    /// new session.
+    pub fn destroy(&self) {
+        let mut state = self.0.lock().unwrap();
+        state.data.clear();
+        state.destroyed = true;
+    }
+}
+
+#[derive(Clone)]
+pub struct Sessions {
+    store: Arc<dyn SessionStore>,
+    cookie_name: String,
+    path: String,
+    domain: Option<String>,
+    secure: bool,
+    same_site: SameSite,
+    idle_timeout: Option<Duration>,
+    absolute_timeout: Option<Duration>,
+}
+
+/// Loads the session of each request from `store` and makes it available as `Session` through
+/// the request extensions. It is saved again after the following middlewares responded, but only
+/// if it changed. This includes errors carrying a response, e.g. `HttpError::Response` with a
+/// redirect, while changes are dropped on any other error since there is no response to set the
+/// cookie on. Sessions expire after 24 hours of inactivity by default.
+///
+/// The session cookie is named `session` and is `HttpOnly`, `Secure` and `SameSite=Lax` unless
+/// configured otherwise.
+pub fn sessions<T: SessionStore + 'static>(store: T) -> Sessions {
+    Sessions {
+        store: Arc::new(store),
+        cookie_name: "session".to_owned(),
+        path: "/".to_owned(),
+        domain: None,
+        secure: true,
+        same_site: SameSite::Lax,
+        idle_timeout: Some(Duration::from_secs(24 * 60 * 60)),
+        absolute_timeout: None,
+    }
+}
+
+impl Sessions {
+    pub fn cookie_name(mut self, name: &str) -> Self {
+        self.cookie_name = name.to_owned();
+        self
+    }
+
+    pub fn path(mut self, path: &str) -> Self {
+        self.path = path.to_owned();
+        self
+    }
+
+    pub fn domain(mut self, domain: &str) -> Self {
+        self.domain = Some(domain.to_owned());
+        self
+    }
+
+    /// Whether the cookie is only sent over HTTPS (defaults to true).
+    pub fn secure(mut self, secure: bool) -> Self {
+        self.secure = secure;
+        self
+    }
+
+    pub fn same_site(mut self, same_site: SameSite) -> Self {
+        self.same_site = same_site;
+        self
+    }
+
+    /// Ends sessions that have not been accessed for `timeout`. The last access is recorded with
+    /// an accuracy of a tenth of the timeout, to not save unchanged sessions on every request.
+    pub fn idle_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.idle_timeout = timeout;
+        self
+    }
+
+    /// Ends sessions `timeout` after they were created, regardless of their activity.
+    pub fn absolute_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.absolute_timeout = timeout;
+        self
+    }
+
+    fn key(&self, req: &Request) -> Option<String> {
+        req.headers()
+            .get_all(COOKIE)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(';'))
+            .filter_map(|pair| {
+                let mut parts = pair.splitn(2, '=');
+                match (parts.next(), parts.next()) {
+                    (Some(name), Some(value)) if name.trim() == self.cookie_name => {
+                        Some(value.trim().trim_matches('"').to_owned())
+                    }
+                    _ => None,
+                }
+            })
+            .find(|value| !value.is_empty())
+    }
+
+    fn expired(&self, record: &Record, now: SystemTime) -> bool {
+        self.idle_timeout
+            .is_some_and(|timeout| elapsed(record.accessed, now) > timeout)
+            || self
+                .absolute_timeout
+                .is_some_and(|timeout| elapsed(record.created, now) > timeout)
+    }
+
+    fn ttl(&self, record: &Record, now: SystemTime) -> Option<Duration> {
+        let remaining = self.absolute_timeout.map(|timeout| {
+            timeout
+                .checked_sub(elapsed(record.created, now))
+                .unwrap_or_default()
+        });
+        match (self.idle_timeout, remaining) {
+            (Some(idle), Some(remaining)) => Some(idle.min(remaining)),
+            (idle, remaining) => idle.or(remaining),
+        }
+    }
+
+    fn set_cookie(&self, res: &mut HttpResponse, key: Option<&str>) {
+        let mut cookie = Cookie::build(self.cookie_name.clone(), key.unwrap_or("").to_owned())
+            .path(self.path.clone())
+            .http_only(true)
+            .secure(self.secure)
+            .same_site(self.same_site);
+        if let Some(ref domain) = self.domain {
+            cookie = cookie.domain(domain.clone());
+        }
+        if key.is_none() {
+            cookie = cookie.max_age(cookie::time::Duration::ZERO);
+        }
+        if let Ok(value) = HeaderValue::from_str(&cookie.finish().to_string()) {
+            res.headers_mut().append(SET_COOKIE, value);
+        }
+    }
+
+    /// Saves the session after the request has been handled.
+    fn commit(
+        &self,
+        mut res: HttpResponse,
+        key: Option<String>,
+        loaded: Option<Record>,
+        session: Session,
+        now: SystemTime,
+    ) -> ResponseFuture {
+        let (data, changed, regenerate, destroyed) = {
+            let mut state = session.0.lock().unwrap();
+            (
+                mem::take(&mut state.data),
+                state.changed,
+                state.regenerate || state.destroyed,
+                state.destroyed,
+            )
+        };
+        let fresh = loaded.is_none() || regenerate;
+
+        // the session the cookie refers to is replaced or does not exist (anymore)
+        let destroy_old = match key {
+            Some(ref key) if fresh => self.store.destroy(key),
+            _ => Box::new(future::ok(())),
+        };
+
+        if fresh && data.is_empty() {
+            let config = self.clone();
+            return Box::new(destroy_old.map(move |()| {
+                if key.is_some() {
+                    config.set_cookie(&mut res, None);
+                }
+                res
+            }));
+        }
+
+        let touch = match (loaded.as_ref(), self.idle_timeout) {
+            (Some(record), Some(timeout)) if !fresh => {
+                elapsed(record.accessed, now) >= timeout / 10
+            }
+            _ => false,
+        };
+        if !changed && !fresh && !touch {
+            return Box::new(future::ok(res));
+        }
+
+        let record = Record {
+            data,
+            created: match loaded {
+                Some(ref record) if !destroyed => record.created,
+                _ => now,
+            },
+            accessed: now,
+        };
+        let ttl = self.ttl(&record, now);
+        let save_key = if fresh { None } else { key.clone() };
+        let config = self.clone();
+        Box::new(
+            destroy_old
+                .and_then(move |()| {
+                    let saved = config.store.save(save_key.as_deref(), &record, ttl);
+                    saved.map(move |value| (config, value))
+                })
+                .map(move |(config, value)| {
+                    if key.as_ref() != Some(&value) {
+                        config.set_cookie(&mut res, Some(&value));
+                    }
+                    res
+                }),
+        )
+    }
+}
+
+impl<S> Middleware<S> for Sessions
+where
+    S: Send + 'static,
+{
+    fn handle(&self, mut req: Request, res: Response, state: S, next: Next<S>) -> ResponseFuture {
+        let key = self.key(&req);
+        let load = match key {
+            Some(ref key) => self.store.load(key),
+            None => Box::new(future::ok(None)),
+        };
+        let config = self.clone();
+        Box::new(load.and_then(move |record| {
+            let now = SystemTime::now();
+            let loaded = record.filter(|record| !config.expired(record, now));
+            let session = Session::new(
+                loaded
+                    .as_ref()
+                    .map(|record| record.data.clone())
+                    .unwrap_or_default(),
+            );
+            req.extensions_mut().insert(session.clone());
+            next(req, res, state).then(move |result| -> ResponseFuture {
+                match result {
+                    Ok(res) => config.commit(res, key, loaded, session, now),
+                    // e.g. a redirect after login
+                    Err(HttpError::Response(res)) => Box::new(
+                        config
+                            .commit(res, key, loaded, session, now)
+                            .and_then(|res| Err(HttpError::Response(res))),
+                    ),
+                    Err(err) => Box::new(future::err(err)),
+                }
+            })
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{sweep, valid_id, Record};
+    use crate::{
+        default_fallback, sessions, App, CookieSessionStore, FileSessionStore, HttpError,
+        HttpResponse, MemorySessionStore, Next, Request, Response, Session, SessionStore, Sessions,
+    };
+    use futures::{Future, Stream};
+    use hyper::header::{COOKIE, SET_COOKIE};
+    use hyper::{Body, StatusCode};
+    use serde_json::Map;
+    use std::sync::Arc;
+    use std::time::{Duration, SystemTime};
+
+    fn app(sessions: Sessions) -> App<()> {
+        let mut app = App::new();
+        app.add(sessions.secure(false));
+        app.add(|req: Request, mut res: Response, _state, _next: Next<()>| {
+            let session = req.extensions().get::<Session>().unwrap();
+            match req.uri().path() {
+                "/login" => {
+                    session.regenerate();
+                    session.set("user", "alice")?;
+                }
+                "/logout" => session.destroy(),
+                "/redirect" => {
+                    session.regenerate();
+                    session.set("user", "alice")?;
+                    let mut res = Response::new();
+                    res.status(StatusCode::SEE_OTHER);
+                    return Err(HttpError::Response(
+                        res.body(Body::empty()).map_err(HttpError::Http)?,
+                    ));
+                }
+                "/visit" => {
+                    let visits = session.get::<u64>("visits").unwrap_or(0);
+                    session.set("visits", visits + 1)?;
+                }
+                _ => {}
+            }
+            let user = session.get::<String>("user").unwrap_or_default();
+            res.status(StatusCode::OK);
+            res.body(Body::from(user)).map_err(HttpError::Http)
+        });
+        app.build()
+    }
+
+    /// Returns the response and the new value of the session cookie, if it was set.
+    fn call(app: &App<()>, path: &str, cookie: Option<&str>) -> (HttpResponse, Option<String>) {
+        let mut req = hyper::Request::get(path);
+        if let Some(cookie) = cookie {
+            req.header(COOKIE, format!("theme=dark; session={}", cookie));
+        }
+        let res = match app
+            .execute(
+                req.body(Body::empty()).unwrap(),
+                Response::new(),
+                (),
+                default_fallback,
+            )
+            .wait()
+        {
+            Ok(res) | Err(HttpError::Response(res)) => res,
+            Err(err) => panic!("{}", err),
+        };
+        let cookie = res.headers().get(SET_COOKIE).map(|value| {
+            let value = value.to_str().unwrap();
+            assert!(value.contains("HttpOnly"));
+            assert!(value.contains("SameSite=Lax"));
+            value["session=".len()..value.find(';').unwrap()].to_owned()
+        });
+        (res, cookie)
+    }
+
+    #[test]
+    fn server_side_sessions() {
+        let store = Arc::new(MemorySessionStore::new());
+        let app = app(sessions(store.clone()));
+
+        // nothing is stored for sessions without data
+        assert_eq!(call(&app, "/", None).1, None);
+
+        let (_, id) = call(&app, "/visit", None);
+        let id = id.unwrap();
+        assert!(valid_id(&id));
+
+        // unchanged sessions are not saved again
+        let (_, cookie) = call(&app, "/", Some(&id));
+        assert_eq!(cookie, None);
+        // the ID stays the same
+        assert_eq!(call(&app, "/visit", Some(&id)).1, None);
+        let record = store.load(&id).wait().unwrap().unwrap();
+        assert_eq!(record.data["visits"], 2);
+
+        // login moves the session to a new ID
+        let (_, new_id) = call(&app, "/login", Some(&id));
+        let new_id = new_id.unwrap();
+        assert_ne!(new_id, id);
+        assert!(store.load(&id).wait().unwrap().is_none());
+        let record = store.load(&new_id).wait().unwrap().unwrap();
+        assert_eq!(record.data["visits"], 2);
+        assert_eq!(record.data["user"], "alice");
+
+        // logout removes the session and the cookie
+        let (_, cookie) = call(&app, "/logout", Some(&new_id));
+        assert_eq!(cookie.unwrap(), "");
+        assert!(store.load(&new_id).wait().unwrap().is_none());
+
+        // unknown sessions are replaced
+        let (_, cookie) = call(&app, "/visit", Some("unknown"));
+        assert!(valid_id(&cookie.unwrap()));
+    }
+
+    #[test]
+    fn saves_on_error_responses() {
+        let store = Arc::new(MemorySessionStore::new());
+        let app = app(sessions(store.clone()));
+
+        let (res, id) = call(&app, "/redirect", None);
+        assert_eq!(res.status(), StatusCode::SEE_OTHER);
+        let record = store.load(&id.unwrap()).wait().unwrap().unwrap();
+        assert_eq!(record.data["user"], "alice");
+    }
+
+    #[test]
+    fn timeouts() {
+        let store = Arc::new(MemorySessionStore::new());
+        let app = app(sessions(store.clone())
+            .idle_timeout(Some(Duration::from_secs(60 * 60)))
+            .absolute_timeout(Some(Duration::from_secs(24 * 60 * 60))));
+        let now = SystemTime::now();
+        let mut data = Map::new();
+        data.insert("user".to_owned(), "alice".into());
+        let save = |created: Duration, accessed: Duration| {
+            let record = Record {
+                data: data.clone(),
+                created: now - created,
+                accessed: now - accessed,
+            };
+            store.save(None, &record, None).wait().unwrap()
+        };
+        let body = |res: HttpResponse| {
+            let body = res.into_body().concat2().wait().unwrap();
+            String::from_utf8(body.to_vec()).unwrap()
+        };
+
+        let active = save(Duration::from_secs(60), Duration::from_secs(1));
+        let (res, cookie) = call(&app, "/", Some(&active));
+        assert_eq!(body(res), "alice");
+        assert_eq!(cookie, None);
+
+        // the access is recorded once a tenth of the idle timeout has passed
+        let idle = save(Duration::from_secs(600), Duration::from_secs(600));
+        let (res, cookie) = call(&app, "/", Some(&idle));
+        assert_eq!(body(res), "alice");
+        assert_eq!(cookie, None);
+        let record = store.load(&idle).wait().unwrap().unwrap();
+        assert!(record.accessed > now - Duration::from_secs(60));
+
+        for expired in [
+            save(
+                Duration::from_secs(3 * 60 * 60),
+                Duration::from_secs(2 * 60 * 60),
+            ),
+            save(Duration::from_secs(25 * 60 * 60), Duration::from_secs(1)),
+        ] {
+            let (res, cookie) = call(&app, "/", Some(&expired));
+            assert_eq!(body(res), "");
+            assert_eq!(cookie.unwrap(), "");
+            assert!(store.load(&expired).wait().unwrap().is_none());
+        }
+    }
+
+    #[test]
+    fn cookie_store() {
+        let app = app(sessions(CookieSessionStore::new(&[7; 32])));
+
+        let (_, cookie) = call(&app, "/login", None);
+        let cookie = cookie.unwrap();
+        assert!(!cookie.contains("alice"));
+        let (res, _) = call(&app, "/", Some(&cookie));
+        assert_eq!(res.status(), StatusCode::OK);
+
+        let store = CookieSessionStore::new(&[7; 32]);
+        let record = store.load(&cookie).wait().unwrap().unwrap();
+        assert_eq!(record.data["user"], "alice");
+
+        let mut tampered = cookie.into_bytes();
+        tampered[10] = if tampered[10] == b'A' { b'B' } else { b'A' };
+        let tampered = String::from_utf8(tampered).unwrap();
+        assert!(store.load(&tampered).wait().unwrap().is_none());
+        assert!(CookieSessionStore::new(&[8; 32])
+            .load(&tampered)
+            .wait()
+            .unwrap()
+            .is_none());
+    }
+
+    #[test]
+    fn file_store() {
+        let dir = std::env::temp_dir().join(format!("web-sessions-{}", std::process::id()));
+        let store = Arc::new(FileSessionStore::new(&dir));
+        let app = app(sessions(store.clone()));
+
+        let (_, id) = call(&app, "/login", None);
+        let id = id.unwrap();
+        assert!(dir.join(&id).exists());
+        let record = store.load(&id).wait().unwrap().unwrap();
+        assert_eq!(record.data["user"], "alice");
+        assert!(store.load("../secret").wait().unwrap().is_none());
+
+        let (_, cookie) = call(&app, "/logout", Some(&id));
+        assert_eq!(cookie.unwrap(), "");
+        assert!(!dir.join(&id).exists());
+
+        // no temporary files are left behind
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 0);
+
+        // corrupt sessions are errors rather than replaced
+        let (_, id) = call(&app, "/login", None);
+        let id = id.unwrap();
+        std::fs::write(dir.join(&id), "{\"record\":").unwrap();
+        assert!(store.load(&id).wait().is_err());
+
+        // expired sessions are swept
+        let expired = r#"{"expires":1,"record":{"created":1,"accessed":1,"data":{}}}"#;
+        std::fs::write(dir.join(&id), expired).unwrap();
+        sweep(&dir).unwrap();
+        assert!(!dir.join(&id).exists());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
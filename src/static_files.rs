@@ -0,0 +1,410 @@
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::{Component, Path, PathBuf};
+
+use crate::encoding::{self, Encoding};
+use crate::{
+    HttpError, HttpResponse, IntoResponse, Middleware, Next, Request, Response, ResponseFuture,
+};
+use futures::stream;
+use hyper::header::{
+    HeaderValue, ACCEPT_RANGES, CONTENT_ENCODING, CONTENT_LENGTH, CONTENT_RANGE, CONTENT_TYPE,
+    ETAG, IF_NONE_MATCH, RANGE, VARY,
+};
+use hyper::{Body, Method, StatusCode};
+use mime_guess::Mime;
+use percent_encoding::percent_decode;
+
+/// Size of the chunks files are streamed in.
+const CHUNK_SIZE: u64 = 64 * 1024;
+
+pub struct StaticFiles {
+    root: PathBuf,
+    precompressed: bool,
+}
+
+/// Serves files from the `root` directory. Requests that do not match a file are passed on to
+/// the next middleware.
+pub fn static_files<P: Into<PathBuf>>(root: P) -> StaticFiles {
+    StaticFiles {
+        root: root.into(),
+        precompressed: true,
+    }
+}
+
+impl StaticFiles {
+    /// Whether to look for precompressed `.br` and `.gz` siblings of the requested file
+    /// (enabled by default).
+    pub fn precompressed(mut self, enabled: bool) -> Self {
+        self.precompressed = enabled;
+        self
+    }
+
+    fn resolve(&self, path: &str) -> Option<PathBuf> {
+        let mut resolved = self.root.join(sanitize(path)?);
+        if resolved.is_dir() {
+            resolved.push("index.html");
+        }
+        if resolved.is_file() {
+            Some(resolved)
+        } else {
+            None
+        }
+    }
+}
+
+/// Turns the percent-encoded request path into a relative file path. Returns `None` for paths
+/// that would escape the directory they are resolved against.
+pub(crate) fn sanitize(path: &str) -> Option<PathBuf> {
+    let decoded = percent_decode(path.as_bytes()).decode_utf8().ok()?;
+    let mut sanitized = PathBuf::new();
+    for component in Path::new(decoded.trim_start_matches('/')).components() {
+        match component {
+            Component::Normal(segment) => sanitized.push(segment),
+            Component::CurDir => {}
+            Component::ParentDir | Component::RootDir | Component::Prefix(_) => return None,
+        }
+    }
+    Some(sanitized)
+}
+
+impl<S> Middleware<S> for StaticFiles
+where
+    S: 'static,
+{
+    fn handle(&self, req: Request, res: Response, state: S, next: Next<S>) -> ResponseFuture {
+        if req.method() != Method::GET && req.method() != Method::HEAD {
+            return next(req, res, state);
+        }
+
+        match self.resolve(req.uri().path()) {
+            Some(path) => serve_file(&req, res, &path, self.precompressed).into_response(),
+            None => next(req, res, state),
+        }
+    }
+}
+
+fn serve_file(
+    req: &Request,
+    mut res: Response,
+    path: &Path,
+    precompressed: bool,
+) -> Result<HttpResponse, HttpError> {
+    let mut encoding = Encoding::Identity;
+    let mut file_path = path.to_path_buf();
+
+    if precompressed {
+        let mut available = [Encoding::Brotli, Encoding::Gzip]
+            .iter()
+            .cloned()
+            .filter(|enc| sibling(path, *enc).is_file())
+            .collect::<Vec<_>>();
+        if !available.is_empty() {
+            res.header(VARY, HeaderValue::from_static("Accept-Encoding"));
+            available.push(Encoding::Identity);
+            if let Some(enc) = encoding::negotiate(req.headers(), &available) {
+                if enc != Encoding::Identity {
+                    file_path = sibling(path, enc);
+                    encoding = enc;
+                }
+            }
+        }
+    }
+
+    let mut file = File::open(&file_path)?;
+    let len = file.metadata()?.len();
+    let mime = mime_guess::from_path(path).first_or_octet_stream();
+
+    respond(req, res, &mime, encoding, None, len, |offset, len| {
+        file.seek(SeekFrom::Start(offset))?;
+        let chunks = FileChunks {
+            file,
+            remaining: len,
+        };
+        Ok(Body::wrap_stream(stream::iter_result(chunks)))
+    })
+}
+
+/// Reads the next `remaining` bytes of `file` in chunks of at most `CHUNK_SIZE`.
+struct FileChunks {
+    file: File,
+    remaining: u64,
+}
+
+impl Iterator for FileChunks {
+    type Item = io::Result<Vec<u8>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.remaining == 0 {
+            return None;
+        }
+        let mut buf = vec![0; self.remaining.min(CHUNK_SIZE) as usize];
+        match self.file.read_exact(&mut buf) {
+            Ok(()) => {
+                self.remaining -= buf.len() as u64;
+                Some(Ok(buf))
+            }
+            Err(err) => {
+                self.remaining = 0;
+                Some(Err(err))
+            }
+        }
+    }
+}
+
+fn sibling(path: &Path, enc: Encoding) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(".");
+    name.push(enc.extension().unwrap_or_default());
+    PathBuf::from(name)
+}
+
+/// Builds the response for a static asset of `len` bytes, honoring `Range` requests and, if an
+/// `etag` is given, `If-None-Match`. `read` is called with the offset and the amount of bytes that
+/// should be sent and returns the body.
+pub(crate) fn respond<F>(
+    req: &Request,
+    mut res: Response,
+    mime: &Mime,
+    encoding: Encoding,
+    etag: Option<&str>,
+    len: u64,
+    read: F,
+) -> Result<HttpResponse, HttpError>
+where
+    F: FnOnce(u64, u64) -> io::Result<Body>,
+{
+    res.header(CONTENT_TYPE, mime.as_ref())
+        .header(ACCEPT_RANGES, HeaderValue::from_static("bytes"));
+    if encoding != Encoding::Identity {
+        res.header(CONTENT_ENCODING, encoding.as_str());
+    }
+    if let Some(etag) = etag {
+        res.header(ETAG, etag);
+        let matches = req
+            .headers()
+            .get(IF_NONE_MATCH)
+            .and_then(|v| v.to_str().ok())
+            .map(|v| {
+                v.split(',')
+                    .any(|tag| tag.trim() == etag || tag.trim() == "*")
+            })
+            .unwrap_or(false);
+        if matches {
+            return res
+                .status(StatusCode::NOT_MODIFIED)
+                .body(Body::empty())
+                .map_err(HttpError::Http);
+        }
+    }
+
+    let (offset, count) = match req
+        .headers()
+        .get(RANGE)
+        .and_then(|range| parse_range(range, len))
+    {
+        Some(Ok((start, end))) => {
+            res.status(StatusCode::PARTIAL_CONTENT).header(
+                CONTENT_RANGE,
+                format!("bytes {}-{}/{}", start, end, len).as_str(),
+            );
+            (start, end - start + 1)
+        }
+        Some(Err(())) => {
+            return res
+                .status(StatusCode::RANGE_NOT_SATISFIABLE)
+                .header(CONTENT_RANGE, format!("bytes */{}", len).as_str())
+                .body(Body::empty())
+                .map_err(HttpError::Http);
+        }
+        None => (0, len),
+    };
+
+    res.header(CONTENT_LENGTH, count);
+    if req.method() == Method::HEAD {
+        return res.body(Body::empty()).map_err(HttpError::Http);
+    }
+
+    let body = read(offset, count)?;
+    res.body(body).map_err(HttpError::Http)
+}
+
+/// Parses a single `bytes` range into an inclusive `(start, end)` pair, or fails if none of it
+/// lies within the content. Multiple ranges, unknown units and malformed headers yield `None`, so
+/// the whole content is sent.
+fn parse_range(header: &HeaderValue, len: u64) -> Option<Result<(u64, u64), ()>> {
+    let spec = match header.to_str() {
+        Ok(s) if s.starts_with("bytes=") && !s.contains(',') => s[6..].trim(),
+        _ => return None,
+    };
+    let mut parts = spec.splitn(2, '-');
+    let (start, end) = (parts.next()?.trim(), parts.next()?.trim());
+
+    let range = if start.is_empty() {
+        // suffix range, e.g. `bytes=-500`
+        let suffix: u64 = end.parse().ok()?;
+        if suffix == 0 || len == 0 {
+            return Some(Err(()));
+        }
+        (len.saturating_sub(suffix), len - 1)
+    } else {
+        let start: u64 = start.parse().ok()?;
+        let end = if end.is_empty() {
+            u64::MAX
+        } else {
+            match end.parse::<u64>().ok()? {
+                end if end < start => return None,
+                end => end,
+            }
+        };
+        (start, end.min(len.saturating_sub(1)))
+    };
+
+    if range.0 >= len {
+        Some(Err(()))
+    } else {
+        Some(Ok(range))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_range, static_files};
+    use crate::{default_fallback, App, Request};
+    use futures::{Future, Stream};
+    use hyper::header::{
+        HeaderValue, ACCEPT_ENCODING, CONTENT_ENCODING, CONTENT_RANGE, CONTENT_TYPE, RANGE,
+    };
+    use hyper::{Body, StatusCode};
+    use std::fs;
+    use std::path::PathBuf;
+
+    fn fixture(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("web-static-{}", name));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("app.js"), "console.log(1)").unwrap();
+        fs::write(dir.join("app.js.gz"), "gzipped").unwrap();
+        fs::write(dir.join("app.js.br"), "brotli").unwrap();
+        dir
+    }
+
+    fn get(dir: PathBuf, req: Request) -> hyper::Response<Vec<u8>> {
+        let mut app = App::new();
+        app.add(static_files(dir));
+        let res = app
+            .build()
+            .execute(req, crate::Response::new(), (), default_fallback)
+            .wait()
+            .unwrap();
+        let (parts, body) = res.into_parts();
+        let body = body.concat2().wait().unwrap().to_vec();
+        hyper::Response::from_parts(parts, body)
+    }
+
+    #[test]
+    fn serves_precompressed_variant() {
+        let req = hyper::Request::get("/app.js")
+            .header(ACCEPT_ENCODING, "gzip;q=1, br;q=0.5")
+            .body(Body::empty())
+            .unwrap();
+        let res = get(fixture("variant"), req);
+
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(res.headers()[CONTENT_ENCODING], "gzip");
+        assert_eq!(
+            res.headers()[CONTENT_TYPE],
+            mime_guess::from_path("app.js")
+                .first_or_octet_stream()
+                .as_ref()
+        );
+        assert_eq!(res.body(), b"gzipped");
+    }
+
+    #[test]
+    fn serves_range_of_original() {
+        let req = hyper::Request::get("/app.js")
+            .header(RANGE, "bytes=0-6")
+            .body(Body::empty())
+            .unwrap();
+        let res = get(fixture("range"), req);
+
+        assert_eq!(res.status(), StatusCode::PARTIAL_CONTENT);
+        assert!(res.headers().get(CONTENT_ENCODING).is_none());
+        assert_eq!(res.body(), b"console");
+    }
+
+    #[test]
+    fn streams_large_files_in_chunks() {
+        let dir = fixture("large");
+        let data = (0..200_000u32).map(|i| i as u8).collect::<Vec<_>>();
+        fs::write(dir.join("large.bin"), &data).unwrap();
+
+        let req = hyper::Request::get("/large.bin")
+            .body(Body::empty())
+            .unwrap();
+        let res = get(dir.clone(), req);
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(res.body(), &data);
+
+        let req = hyper::Request::get("/large.bin")
+            .header(RANGE, "bytes=65000-140000")
+            .body(Body::empty())
+            .unwrap();
+        let res = get(dir, req);
+        assert_eq!(res.status(), StatusCode::PARTIAL_CONTENT);
+        assert_eq!(res.body(), &data[65000..=140000]);
+    }
+
+    #[test]
+    fn ignores_unsupported_ranges() {
+        let dir = fixture("unsupported-range");
+        fs::write(dir.join("empty.txt"), "").unwrap();
+
+        for range in &["bytes=0-1,3-4", "items=0-1", "bytes=4-2"] {
+            let req = hyper::Request::get("/app.js")
+                .header(RANGE, *range)
+                .body(Body::empty())
+                .unwrap();
+            let res = get(dir.clone(), req);
+            assert_eq!(res.status(), StatusCode::OK);
+            assert!(res.headers().get(CONTENT_RANGE).is_none());
+            assert_eq!(res.body(), b"console.log(1)");
+        }
+
+        let req = hyper::Request::get("/empty.txt")
+            .header(RANGE, "bytes=0-")
+            .body(Body::empty())
+            .unwrap();
+        let res = get(dir, req);
+        assert_eq!(res.status(), StatusCode::RANGE_NOT_SATISFIABLE);
+        assert_eq!(res.headers()[CONTENT_RANGE], "bytes */0");
+    }
+
+    #[test]
+    fn does_not_escape_root() {
+        let req = hyper::Request::get("/../web-static-escape/app.js")
+            .body(Body::empty())
+            .unwrap();
+        let res = get(fixture("escape"), req);
+
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[test]
+    fn range_parsing() {
+        let parse = |s| parse_range(&HeaderValue::from_static(s), 10);
+        assert_eq!(parse("bytes=2-4"), Some(Ok((2, 4))));
+        assert_eq!(parse("bytes=5-"), Some(Ok((5, 9))));
+        assert_eq!(parse("bytes=-3"), Some(Ok((7, 9))));
+        assert_eq!(parse("bytes=8-20"), Some(Ok((8, 9))));
+        assert_eq!(parse("bytes=10-"), Some(Err(())));
+        assert_eq!(parse("bytes=-0"), Some(Err(())));
+        assert_eq!(parse("bytes=0-1,3-4"), None);
+        assert_eq!(parse("items=0-1"), None);
+        assert_eq!(parse("bytes=x-1"), None);
+        assert_eq!(
+            parse_range(&HeaderValue::from_static("bytes=-1"), 0),
+            Some(Err(()))
+        );
+    }
+}
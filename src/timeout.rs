@@ -0,0 +1,168 @@
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::{HttpError, Middleware, Next, Request, Response, ResponseFuture};
+use futures::future::{Either, Future};
+use hyper::{Method, StatusCode, Uri};
+use tokio_timer::Delay;
+
+type Hook = dyn Fn(&Method, &Uri) + Send + Sync;
+
+#[derive(Clone)]
+pub struct Timeout {
+    duration: Duration,
+    status: StatusCode,
+    hook: Option<Arc<Hook>>,
+}
+
+/// Fails requests that take longer than `duration` with `503 Service Unavailable`. The
+/// middlewares after this one are dropped and thereby cancelled once the time is up. Add it to a
+/// mounted app to only limit the requests below the mount path.
+///
+/// Requires the tokio timer, which is available when running within a hyper server.
+pub fn timeout(duration: Duration) -> Timeout {
+    Timeout {
+        duration,
+        status: StatusCode::SERVICE_UNAVAILABLE,
+        hook: None,
+    }
+}
+
+impl Timeout {
+    /// The status to respond with, e.g. `408 Request Timeout` (defaults to
+    /// `503 Service Unavailable`).
+    pub fn status(mut self, status: StatusCode) -> Self {
+        self.status = status;
+        self
+    }
+
+    /// Gets called with the method and URI of every request that timed out.
+    pub fn on_timeout<F>(mut self, hook: F) -> Self
+    where
+        F: Fn(&Method, &Uri) + Send + Sync + 'static,
+    {
+        self.hook = Some(Arc::new(hook));
+        self
+    }
+}
+
+impl<S> Middleware<S> for Timeout
+where
+    S: 'static,
+{
+    fn handle(&self, req: Request, res: Response, state: S, next: Next<S>) -> ResponseFuture {
+        let request = self
+            .hook
+            .as_ref()
+            .map(|hook| (hook.clone(), req.method().clone(), req.uri().clone()));
+        let status = self.status;
+        let delay = Delay::new(Instant::now() + self.duration);
+
+        Box::new(
+            delay
+                .select2(next(req, res, state))
+                .then(move |result| match result {
+                    // the unfinished response future is dropped here
+                    Ok(Either::A(_)) => {
+                        if let Some((hook, method, uri)) = request {
+                            hook(&method, &uri);
+                        }
+                        Err(HttpError::Status(status))
+                    }
+                    Ok(Either::B((res, _))) => Ok(res),
+                    Err(Either::A((err, _))) => Err(HttpError::internal(err)),
+                    Err(Either::B((err, _))) => Err(err),
+                }),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{default_fallback, timeout, App, HttpError, HttpResponse, Next, Request, Response};
+    use futures::future::{self, Future};
+    use futures::sync::oneshot;
+    use hyper::{Body, StatusCode};
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+    use tokio::runtime::current_thread::Runtime;
+
+    struct Dropped(Arc<Mutex<bool>>);
+
+    impl Drop for Dropped {
+        fn drop(&mut self) {
+            *self.0.lock().unwrap() = true;
+        }
+    }
+
+    #[test]
+    fn times_out() {
+        let dropped = Arc::new(Mutex::new(false));
+        let timed_out = Arc::new(Mutex::new(Vec::new()));
+
+        let mut app = App::new();
+        {
+            let timed_out = timed_out.clone();
+            app.add(
+                timeout(Duration::from_millis(10))
+                    .status(StatusCode::REQUEST_TIMEOUT)
+                    .on_timeout(move |method, uri| {
+                        timed_out
+                            .lock()
+                            .unwrap()
+                            .push(format!("{} {}", method, uri));
+                    }),
+            );
+        }
+        {
+            let dropped = dropped.clone();
+            app.add(
+                move |_req: Request, _res: Response, _state, _next: Next<()>| {
+                    // never resolves
+                    let (tx, rx) = oneshot::channel::<HttpResponse>();
+                    let guard = Dropped(dropped.clone());
+                    rx.map_err(|_| HttpError::Status(StatusCode::INTERNAL_SERVER_ERROR))
+                        .map(move |res| {
+                            let _ = (&guard, &tx);
+                            res
+                        })
+                },
+            );
+        }
+
+        let req = hyper::Request::get("/slow").body(Body::empty()).unwrap();
+        let result = Runtime::new().unwrap().block_on(app.build().execute(
+            req,
+            Response::new(),
+            (),
+            default_fallback,
+        ));
+        match result {
+            Err(HttpError::Status(status)) => assert_eq!(status, StatusCode::REQUEST_TIMEOUT),
+            _ => panic!("expected request timeout"),
+        }
+        assert!(*dropped.lock().unwrap());
+        assert_eq!(*timed_out.lock().unwrap(), vec!["GET /slow"]);
+    }
+
+    #[test]
+    fn passes_fast_responses() {
+        let mut app = App::new();
+        app.add(timeout(Duration::from_secs(10)));
+        app.add(
+            |_req: Request, mut res: Response, _state, _next: Next<()>| {
+                future::result(res.body(Body::from("fast")).map_err(HttpError::Http))
+            },
+        );
+
+        let req = hyper::Request::get("/").body(Body::empty()).unwrap();
+        let res = Runtime::new()
+            .unwrap()
+            .block_on(
+                app.build()
+                    .execute(req, Response::new(), (), default_fallback),
+            )
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+    }
+}